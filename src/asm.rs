@@ -0,0 +1,134 @@
+//! The x86-64 assembly backend.
+//! Translates a compiled brainfuck `Program` into a standalone
+//! GNU `as` source file for Linux that uses raw `read`/`write`
+//! syscalls, so it can be linked without libc:
+//!
+//! ```text
+//! $ bfrs emit asm prog.bf -o prog.s
+//! $ as prog.s -o prog.o && ld prog.o -o prog
+//! ```
+
+use std::fmt::Write;
+
+use crate::{compiler::Program, instruction::Instruction::*, vm::MEM};
+
+/// Emit the given `Program` as x86-64 assembly (AT&T syntax).
+///
+/// The data pointer lives in `%rbx` for the lifetime of the program.
+/// Reading past the end of input stores 0 in the current cell, like the `VM`.
+pub fn emit(program: &Program) -> String {
+    let mut out = String::new();
+
+    // writing into a String can't fail, so unwrapping is fine throughout
+    writeln!(out, "\t.section .bss").unwrap();
+    writeln!(out, "\t.lcomm tape, {MEM}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "\t.section .text").unwrap();
+    writeln!(out, "\t.globl _start").unwrap();
+    writeln!(out, "_start:").unwrap();
+    writeln!(out, "\tleaq tape(%rip), %rbx").unwrap();
+
+    for (ptr, ins) in program.instructions.iter().enumerate() {
+        match *ins {
+            Shift(count) => {
+                writeln!(out, "\taddq ${count}, %rbx").unwrap();
+            }
+            Alt(amount) => {
+                // cells wrap, so only the low byte of the amount matters
+                writeln!(out, "\taddb ${}, (%rbx)", amount as u8).unwrap();
+            }
+            Out => {
+                writeln!(out, "\tcall putch").unwrap();
+            }
+            In => {
+                writeln!(out, "\tcall getch").unwrap();
+            }
+            Loop => {
+                // jump past the matching End if the cell is zero,
+                // labels are named after the instruction they follow
+                writeln!(out, "\tcmpb $0, (%rbx)").unwrap();
+                writeln!(out, "\tje .L{}", program.loop_map[ptr]).unwrap();
+                writeln!(out, ".L{ptr}:").unwrap();
+            }
+            End => {
+                writeln!(out, "\tcmpb $0, (%rbx)").unwrap();
+                writeln!(out, "\tjne .L{}", program.loop_map[ptr]).unwrap();
+                writeln!(out, ".L{ptr}:").unwrap();
+            }
+            Clear => {
+                writeln!(out, "\tmovb $0, (%rbx)").unwrap();
+            }
+            CopyClear { mul, offset } => {
+                // like the loop it replaces, the target cell is
+                // only touched when the current cell is nonzero
+                writeln!(out, "\tmovzbl (%rbx), %eax").unwrap();
+                writeln!(out, "\ttestl %eax, %eax").unwrap();
+                writeln!(out, "\tjz .L{ptr}").unwrap();
+                if mul != 1 {
+                    writeln!(out, "\timull ${mul}, %eax, %eax").unwrap();
+                }
+                writeln!(out, "\taddb %al, {offset}(%rbx)").unwrap();
+                writeln!(out, "\tmovb $0, (%rbx)").unwrap();
+                writeln!(out, ".L{ptr}:").unwrap();
+            }
+        }
+    }
+
+    // exit(0)
+    writeln!(out, "\tmovl $60, %eax").unwrap();
+    writeln!(out, "\txorl %edi, %edi").unwrap();
+    writeln!(out, "\tsyscall").unwrap();
+    writeln!(out).unwrap();
+
+    // write(1, %rbx, 1)
+    writeln!(out, "putch:").unwrap();
+    writeln!(out, "\tmovl $1, %eax").unwrap();
+    writeln!(out, "\tmovl $1, %edi").unwrap();
+    writeln!(out, "\tmovq %rbx, %rsi").unwrap();
+    writeln!(out, "\tmovl $1, %edx").unwrap();
+    writeln!(out, "\tsyscall").unwrap();
+    writeln!(out, "\tret").unwrap();
+    writeln!(out).unwrap();
+
+    // read(0, %rbx, 1), the cell is cleared first so it stays 0 at EOF
+    writeln!(out, "getch:").unwrap();
+    writeln!(out, "\tmovb $0, (%rbx)").unwrap();
+    writeln!(out, "\txorl %eax, %eax").unwrap();
+    writeln!(out, "\txorl %edi, %edi").unwrap();
+    writeln!(out, "\tmovq %rbx, %rsi").unwrap();
+    writeln!(out, "\tmovl $1, %edx").unwrap();
+    writeln!(out, "\tsyscall").unwrap();
+    writeln!(out, "\tret").unwrap();
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn test_emit_loop_labels() {
+        let asm = emit(&compile("+[.-]"));
+
+        // Loop and End jump to each other's labels
+        assert!(asm.contains("\tje .L4\n.L1:\n"));
+        assert!(asm.contains("\tjne .L1\n.L4:\n"));
+    }
+
+    #[test]
+    fn test_emit_optimized_instructions() {
+        let asm = emit(&compile("[-]>++[-<<+++>>]"));
+
+        assert!(asm.contains("\tmovb $0, (%rbx)\n"));
+        assert!(asm.contains("\timull $3, %eax, %eax\n\taddb %al, -2(%rbx)\n"));
+    }
+
+    #[test]
+    fn test_emit_wrapping_alt() {
+        let asm = emit(&compile("-"));
+
+        assert!(asm.contains("\taddb $255, (%rbx)\n"));
+    }
+}
@@ -0,0 +1,408 @@
+//! The bytecode module. Defines a versioned binary format
+//! for compiled brainfuck `Program`s so they can be saved
+//! and run later without re-parsing and re-optimizing.
+//!
+//! Layout (all integers little-endian):
+//! ```text
+//! magic         4 bytes   b"BFRS"
+//! version       u16
+//! flags         u16       bit 0: source map present
+//! input count   u32       instructions before optimization
+//! count         u32       number of instructions
+//! instructions  count x   opcode u8, followed by its operands
+//! loop_map      count x   u32
+//! source map    count x   (u32 start, u32 end), if present
+//! ```
+
+use std::fmt::Display;
+
+use crate::{
+    compiler::{match_loops, CompilerStats, Program, Span},
+    instruction::Instruction::{self, *},
+    vm::MEM,
+};
+
+/// Magic bytes identifying a bytecode file
+pub const MAGIC: &[u8; 4] = b"BFRS";
+
+/// Current bytecode format version
+pub const VERSION: u16 = 1;
+
+/// Flag set when the file contains a source map
+const FLAG_SOURCE_MAP: u16 = 1;
+
+// Instruction opcodes
+const OP_SHIFT: u8 = 0;
+const OP_ALT: u8 = 1;
+const OP_OUT: u8 = 2;
+const OP_IN: u8 = 3;
+const OP_LOOP: u8 = 4;
+const OP_END: u8 = 5;
+const OP_CLEAR: u8 = 6;
+const OP_COPY_CLEAR: u8 = 7;
+
+/// Errors produced when loading invalid bytecode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BytecodeError {
+    /// Data does not start with `MAGIC`
+    BadMagic,
+    /// Data was written by an unsupported format version
+    UnsupportedVersion(u16),
+    /// Data ended in the middle of a section
+    UnexpectedEof,
+    /// Unrecognized opcode at the given byte offset
+    UnknownOpcode { opcode: u8, offset: usize },
+    /// Data continues past the end of the program
+    TrailingBytes,
+    /// Operand the VM can't run at the given byte offset, like
+    /// `Alt(i16::MIN)` or a `CopyClear` offset outside of memory
+    InvalidOperand { offset: usize },
+    /// Loop map does not match the program's loops
+    InvalidLoopMap,
+    /// Source map contains an inverted span
+    InvalidSourceMap,
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BytecodeError::BadMagic => write!(f, "not a brainfrick bytecode file"),
+            BytecodeError::UnsupportedVersion(v) => write!(f, "unsupported bytecode version {v}"),
+            BytecodeError::UnexpectedEof => write!(f, "unexpected end of bytecode"),
+            BytecodeError::UnknownOpcode { opcode, offset } => {
+                write!(f, "unknown opcode {opcode:#04x} at offset {offset}")
+            }
+            BytecodeError::InvalidOperand { offset } => write!(f, "invalid operand at offset {offset}"),
+            BytecodeError::TrailingBytes => write!(f, "trailing bytes after program"),
+            BytecodeError::InvalidLoopMap => write!(f, "loop map does not match program"),
+            BytecodeError::InvalidSourceMap => write!(f, "invalid source map"),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+impl Program {
+    /// Serialize this `Program` to bytecode.
+    /// The source map is included if the program has one.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let flags = match self.source_map {
+            Some(_) => FLAG_SOURCE_MAP,
+            None => 0,
+        };
+
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&flags.to_le_bytes());
+        put_u32(&mut out, self.stats.input_instructions);
+        put_u32(&mut out, self.instructions.len());
+
+        for ins in &self.instructions {
+            out.push(opcode(ins));
+            match *ins {
+                Shift(count) => out.extend_from_slice(&(count as i64).to_le_bytes()),
+                Alt(amount) => out.extend_from_slice(&amount.to_le_bytes()),
+                CopyClear { mul, offset } => {
+                    out.push(mul);
+                    out.extend_from_slice(&(offset as i64).to_le_bytes());
+                }
+                Out | In | Loop | End | Clear => {}
+            }
+        }
+
+        for target in &self.loop_map {
+            put_u32(&mut out, *target);
+        }
+
+        if let Some(source_map) = &self.source_map {
+            for span in source_map {
+                put_u32(&mut out, span.start);
+                put_u32(&mut out, span.end);
+            }
+        }
+
+        out
+    }
+
+    /// Deserialize a `Program` from bytecode, validating
+    /// its structure and loop map.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, BytecodeError> {
+        let mut reader = Reader::new(bytes);
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(BytecodeError::BadMagic);
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version));
+        }
+
+        let flags = reader.u16()?;
+        let input_instructions = reader.u32()? as usize;
+        let count = reader.u32()? as usize;
+
+        // every instruction takes at least one byte, so this
+        // guards the allocation below against bogus counts
+        if count > bytes.len() {
+            return Err(BytecodeError::UnexpectedEof);
+        }
+
+        let mut instructions = Vec::with_capacity(count);
+        for _ in 0..count {
+            let offset = reader.pos;
+            let ins = match reader.u8()? {
+                OP_SHIFT => Shift(reader.i64()? as isize),
+                OP_ALT => Alt(i16::from_le_bytes(reader.array()?)),
+                OP_OUT => Out,
+                OP_IN => In,
+                OP_LOOP => Loop,
+                OP_END => End,
+                OP_CLEAR => Clear,
+                OP_COPY_CLEAR => CopyClear {
+                    mul: reader.u8()?,
+                    offset: reader.i64()? as isize,
+                },
+                opcode => return Err(BytecodeError::UnknownOpcode { opcode, offset }),
+            };
+
+            // the compiler never produces these, and the VM can't run them
+            match ins {
+                Alt(i16::MIN) => return Err(BytecodeError::InvalidOperand { offset }),
+                CopyClear { offset: target, .. } if target.unsigned_abs() >= MEM => {
+                    return Err(BytecodeError::InvalidOperand { offset })
+                }
+                _ => instructions.push(ins),
+            }
+        }
+
+        let mut loop_map = Vec::with_capacity(count);
+        for _ in 0..count {
+            loop_map.push(reader.u32()? as usize);
+        }
+
+        // the stored loop map must agree with the program's loops
+        if match_loops(&instructions).as_ref() != Some(&loop_map) {
+            return Err(BytecodeError::InvalidLoopMap);
+        }
+
+        let source_map = match flags & FLAG_SOURCE_MAP {
+            0 => None,
+            _ => {
+                let mut source_map = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let span = Span {
+                        start: reader.u32()? as usize,
+                        end: reader.u32()? as usize,
+                    };
+
+                    if span.start > span.end {
+                        return Err(BytecodeError::InvalidSourceMap);
+                    }
+
+                    source_map.push(span);
+                }
+
+                Some(source_map)
+            }
+        };
+
+        if reader.pos != bytes.len() {
+            return Err(BytecodeError::TrailingBytes);
+        }
+
+        Ok(Program {
+            instructions,
+            loop_map,
+            source_map,
+            stats: CompilerStats {
+                input_instructions,
+                output_instructions: count,
+            },
+        })
+    }
+
+    /// Returns true if the given bytes look like bytecode
+    pub fn is_bytecode(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+}
+
+/// Opcode identifying the kind of an `Instruction`, without its operands
+pub(crate) fn opcode(ins: &Instruction) -> u8 {
+    match ins {
+        Shift(_) => OP_SHIFT,
+        Alt(_) => OP_ALT,
+        Out => OP_OUT,
+        In => OP_IN,
+        Loop => OP_LOOP,
+        End => OP_END,
+        Clear => OP_CLEAR,
+        CopyClear { .. } => OP_COPY_CLEAR,
+    }
+}
+
+/// Helper to append a `usize` as a little-endian u32
+pub(crate) fn put_u32(out: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("program too large for bytecode");
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Cursor over bytecode being deserialized
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    /// Everything not read yet
+    pub(crate) fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.pos..];
+        self.pos = self.bytes.len();
+        rest
+    }
+
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], BytecodeError> {
+        let slice = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or(BytecodeError::UnexpectedEof)?;
+
+        self.pos += n;
+        Ok(slice)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N], BytecodeError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, BytecodeError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, BytecodeError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, BytecodeError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn i64(&mut self) -> Result<i64, BytecodeError> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn test_round_trip() {
+        let p = compile(include_str!("../samples/mandelbrot.bf"));
+        let loaded = Program::from_bytes(&p.to_bytes()).unwrap();
+
+        assert_eq!(p.instructions, loaded.instructions);
+        assert_eq!(p.loop_map, loaded.loop_map);
+        assert_eq!(p.source_map, loaded.source_map);
+    }
+
+    #[test]
+    fn test_round_trip_without_source_map() {
+        let mut p = compile("+[->++<]>.");
+        p.source_map = None;
+        let loaded = Program::from_bytes(&p.to_bytes()).unwrap();
+
+        assert_eq!(p.instructions, loaded.instructions);
+        assert_eq!(None, loaded.source_map);
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let mut bytes = compile("+.").to_bytes();
+        bytes[1] = b'X';
+        assert_eq!(
+            Err(BytecodeError::BadMagic),
+            Program::from_bytes(&bytes).map(|_| ())
+        );
+
+        let mut bytes = compile("+.").to_bytes();
+        bytes[4] = 99;
+        assert_eq!(
+            Err(BytecodeError::UnsupportedVersion(99)),
+            Program::from_bytes(&bytes).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let bytes = compile("+[>.<-]").to_bytes();
+
+        for len in 0..bytes.len() {
+            assert!(Program::from_bytes(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_invalid_operand() {
+        // header for a program of one instruction, then the instruction at offset 16
+        let header = |bytes: &mut Vec<u8>| {
+            bytes.extend_from_slice(MAGIC);
+            bytes.extend_from_slice(&VERSION.to_le_bytes());
+            bytes.extend_from_slice(&0u16.to_le_bytes());
+            bytes.extend_from_slice(&1u32.to_le_bytes());
+            bytes.extend_from_slice(&1u32.to_le_bytes());
+        };
+
+        let mut bytes = Vec::new();
+        header(&mut bytes);
+        bytes.push(OP_ALT);
+        bytes.extend_from_slice(&i16::MIN.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        assert_eq!(
+            Err(BytecodeError::InvalidOperand { offset: 16 }),
+            Program::from_bytes(&bytes).map(|_| ())
+        );
+
+        let mut bytes = Vec::new();
+        header(&mut bytes);
+        bytes.push(OP_COPY_CLEAR);
+        bytes.push(1);
+        bytes.extend_from_slice(&(isize::MAX as i64).to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        assert_eq!(
+            Err(BytecodeError::InvalidOperand { offset: 16 }),
+            Program::from_bytes(&bytes).map(|_| ())
+        );
+
+        // the same file with a valid offset loads
+        bytes[18..26].copy_from_slice(&(-3i64).to_le_bytes());
+        assert!(Program::from_bytes(&bytes).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_loop_map() {
+        let p = compile("[.][.]");
+        let mut bytes = p.to_bytes();
+
+        // point the first Loop at the second End, the loop map
+        // is followed by 8 bytes of source map per instruction
+        let count = p.instructions.len();
+        let loop_map_start = bytes.len() - 8 * count - 4 * count;
+        bytes[loop_map_start] = 5;
+        assert_eq!(
+            Err(BytecodeError::InvalidLoopMap),
+            Program::from_bytes(&bytes).map(|_| ())
+        );
+    }
+}
@@ -4,20 +4,82 @@
 
 use std::fmt::Display;
 
-use crate::instruction::Instruction;
+use crate::{
+    instruction::Instruction,
+    ir::{self, Node},
+};
 
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
-struct CompilerStats {
-    input_instructions: usize,
-    output_instructions: usize,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CompilerStats {
+    pub(crate) input_instructions: usize,
+    pub(crate) output_instructions: usize,
 }
 
-#[derive(Debug, Clone)]
+/// Byte range of the source code an `Instruction` was compiled from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// Smallest `Span` covering both `self` and `other`
+    pub fn join(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+
+    /// 1-based line and column of the start of this `Span` in `src`
+    pub fn line_col(&self, src: &str) -> (usize, usize) {
+        let before = &src[..self.start];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (line, before[line_start..].chars().count() + 1)
+    }
+}
+
+/// An `Instruction` along with the source code it was compiled from
+pub type Spanned = (Instruction, Span);
+
+/// Error for brainfuck source that can't be compiled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompileError {
+    /// A `[` without a matching `]`
+    UnmatchedLoop(Span),
+    /// A `]` without a matching `[`
+    UnmatchedEnd(Span),
+}
+
+impl CompileError {
+    /// Source `Span` of the offending instruction
+    pub fn span(&self) -> Span {
+        match self {
+            CompileError::UnmatchedLoop(span) | CompileError::UnmatchedEnd(span) => *span,
+        }
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::UnmatchedLoop(_) => write!(f, "unmatched `[`"),
+            CompileError::UnmatchedEnd(_) => write!(f, "unmatched `]`"),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Program {
     pub instructions: Vec<Instruction>,
     pub loop_map: Vec<usize>,
-    stats: CompilerStats,
+    /// Source `Span` of each instruction, if known
+    pub source_map: Option<Vec<Span>>,
+    pub(crate) stats: CompilerStats,
 }
 
 /// Pretty display for Program
@@ -41,25 +103,69 @@ impl Display for Program {
     }
 }
 
+impl Program {
+    /// Append `other` to the end of this program, so a VM that
+    /// has finished running this program can continue with `other`.
+    /// The source map is dropped, since the two programs were compiled
+    /// from different sources.
+    pub fn append(&mut self, other: Program) {
+        self.instructions.extend(other.instructions);
+        // both programs are balanced, so the combined program is too
+        self.loop_map = match_loops(&self.instructions).expect("program has unbalanced loops");
+        self.source_map = None;
+        self.stats.input_instructions += other.stats.input_instructions;
+        self.stats.output_instructions += other.stats.output_instructions;
+    }
+}
+
+/// Compile brainfuck source into a `Program`.
+/// Panics if the loops are unbalanced, see `try_compile`.
 pub fn compile(src: &str) -> Program {
-    // clean and parse input
-    let mut instructions = src
-        .chars()
-        .map(Instruction::try_from)
-        .filter_map(Result::ok)
-        .collect::<Vec<_>>();
+    try_compile(src).unwrap_or_else(|err| panic!("program has unbalanced loops: {err}"))
+}
+
+/// Check brainfuck source for errors without compiling it
+pub fn check(src: &str) -> Result<(), CompileError> {
+    ir::build(parse(src)).map(|_| ())
+}
 
+/// Compile brainfuck source into a `Program`
+pub fn try_compile(src: &str) -> Result<Program, CompileError> {
+    // clean and parse input
+    let instructions = parse(src);
     let input_instructions = instructions.len();
 
-    // apply each optmiziation to the instructions
+    // build the loop tree
+    let mut nodes = ir::build(instructions)?;
+
+    // apply each optmiziation to the tree
     for optimizer in get_optimizers() {
-        instructions = optimizer.optimize(instructions);
+        nodes = optimizer.optimize(nodes);
     }
 
+    // flatten the tree back into instructions
+    let (instructions, source_map): (Vec<_>, Vec<_>) = ir::lower(nodes).into_iter().unzip();
     let output_instructions = instructions.len();
 
     // match loop instructions, must happen last because
     // optimizers can change position of loop instructions.
+    let loop_map = match_loops(&instructions).expect("program has unbalanced loops");
+
+    // Return the final compiled Program
+    Ok(Program {
+        instructions,
+        loop_map,
+        source_map: Some(source_map),
+        stats: CompilerStats {
+            input_instructions,
+            output_instructions,
+        },
+    })
+}
+
+/// Match each Loop instruction with its End instruction, and vice versa.
+/// Returns `None` if the loops are unbalanced.
+pub(crate) fn match_loops(instructions: &[Instruction]) -> Option<Vec<usize>> {
     // we could obviously use a map (even with identity hash) here to
     // save memory, but the perf gain of using a vec is massive (-35% time).
     let mut loop_map = vec![0; instructions.len()];
@@ -68,7 +174,7 @@ pub fn compile(src: &str) -> Program {
         match *ins {
             Instruction::Loop => stack.push(ptr),
             Instruction::End => {
-                let open = stack.pop().unwrap();
+                let open = stack.pop()?;
                 loop_map[open] = ptr;
                 loop_map[ptr] = open;
             }
@@ -76,18 +182,21 @@ pub fn compile(src: &str) -> Program {
         }
     }
 
-    // ensure our program always has balanced loops
-    assert_eq!(0, stack.len());
+    stack.is_empty().then_some(loop_map)
+}
 
-    // Return the final compiled Program
-    Program {
-        instructions,
-        loop_map,
-        stats: CompilerStats {
-            input_instructions,
-            output_instructions,
-        },
-    }
+/// Parse brainfuck source into `Instruction`s, skipping
+/// any non-instruction characters
+pub fn parse(src: &str) -> Vec<Spanned> {
+    src.char_indices()
+        .filter_map(|(pos, c)| {
+            let span = Span {
+                start: pos,
+                end: pos + c.len_utf8(),
+            };
+            Instruction::try_from(c).ok().map(|ins| (ins, span))
+        })
+        .collect()
 }
 
 /// Returns optimizers to apply to program and their ordering
@@ -106,7 +215,7 @@ fn get_optimizers() -> Vec<OptimizerType> {
 }
 
 pub trait Optimizer {
-    fn optimize(&self, instructions: Vec<Instruction>) -> Vec<Instruction>;
+    fn optimize(&self, nodes: Vec<Node>) -> Vec<Node>;
 }
 
 enum OptimizerType {
@@ -117,138 +226,171 @@ enum OptimizerType {
 }
 
 impl Optimizer for OptimizerType {
-    // enum dispatch to apply optimization function on source instructions
-    fn optimize(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+    // enum dispatch to apply optimization function on the loop tree
+    fn optimize(&self, nodes: Vec<Node>) -> Vec<Node> {
         match self {
-            OptimizerType::Contraction => contraction_optimizer(instructions),
-            OptimizerType::ClearLoop => clear_loop_optimizer(instructions),
-            OptimizerType::CopyLoop => copy_loop_optimizer(instructions),
-            OptimizerType::NoOpReducer => no_op_optimizer(instructions),
+            OptimizerType::Contraction => contraction_optimizer(nodes),
+            OptimizerType::ClearLoop => clear_loop_optimizer(nodes),
+            OptimizerType::CopyLoop => copy_loop_optimizer(nodes),
+            OptimizerType::NoOpReducer => no_op_optimizer(nodes),
         }
     }
 }
 
 /// Replace consecutive Shift and Alt instructions with single instructions.
-fn contraction_optimizer(mut instructions: Vec<Instruction>) -> Vec<Instruction> {
-    let mut output = Vec::new();
-    let mut input = instructions.drain(..).peekable();
-    let mut next: Option<Instruction> = input.next();
+fn contraction_optimizer(nodes: Vec<Node>) -> Vec<Node> {
+    use Instruction::*;
+    let mut output: Vec<Node> = Vec::new();
 
-    while let Some(cur) = next {
-        match cur {
+    for node in nodes {
+        match (output.last_mut(), node) {
             // ex: ">><>>" -> Shift(3)
-            Instruction::Shift(mut count) => {
-                while let Some(Instruction::Shift(more)) = input.peek() {
-                    count += *more;
-                    input.next();
-                }
-
-                output.push(Instruction::Shift(count));
+            (Some(Node::Ins(Shift(count), span)), Node::Ins(Shift(more), more_span)) => {
+                *count += more;
+                *span = span.join(more_span);
             }
             // ex: "+--+-" -> Alt(-1)
-            Instruction::Alt(mut count) => {
-                while let Some(Instruction::Alt(more)) = input.peek() {
-                    count += *more;
-                    input.next();
-                }
-
-                output.push(Instruction::Alt(count));
+            (Some(Node::Ins(Alt(count), span)), Node::Ins(Alt(more), more_span)) => {
+                *count += more;
+                *span = span.join(more_span);
             }
-            other => output.push(other),
+            (_, Node::Loop { body, span }) => output.push(Node::Loop {
+                body: contraction_optimizer(body),
+                span,
+            }),
+            (_, other) => output.push(other),
         }
-
-        next = input.next();
     }
 
     output
 }
 
 /// Replace loops to clear the current cell with Clear instructions
-fn clear_loop_optimizer(instructions: Vec<Instruction>) -> Vec<Instruction> {
+fn clear_loop_optimizer(nodes: Vec<Node>) -> Vec<Node> {
     use Instruction::*;
-    let mut output: Vec<Instruction> = Vec::new();
-
-    for instruction in instructions {
-        output.push(instruction);
 
-        if output.len() >= 3 {
-            // ex: "[-]" -> Clear
-            if let [Loop, Alt(-1), End] = output[output.len() - 3..] {
-                remove_n(&mut output, 3);
-                output.push(Clear);
-            };
-        }
-    }
-
-    output
+    map_loops(nodes, clear_loop_optimizer, |body, span| match body[..] {
+        // ex: "[-]" -> Clear
+        [Node::Ins(Alt(-1), _)] => Some(Node::Ins(Clear, span)),
+        _ => None,
+    })
 }
 
 /// Replace copy-to/multiply loops with CopyClear instructions
-fn copy_loop_optimizer(instructions: Vec<Instruction>) -> Vec<Instruction> {
+fn copy_loop_optimizer(nodes: Vec<Node>) -> Vec<Node> {
     use Instruction::*;
-    let mut output = Vec::new();
-
-    for instruction in instructions {
-        output.push(instruction);
-
-        if output.len() >= 6 {
-            match output[output.len() - 6..] {
-                // ex: "[->>>++<<<]" -> CopyClear { mul: 2, offset: 3 }
-                [Loop, Alt(-1), Shift(off1), Alt(x), Shift(off2), End] if x > 0 && off1 == -off2 => {
-                    remove_n(&mut output, 6);
-
-                    output.push(CopyClear {
-                        mul: x as u8,
-                        offset: off1,
-                    });
-                }
-                // ex: "[>>---<<+-]" -> CopyClear { mul: -3, offset: 2 }
-                [Loop, Shift(off1), Alt(x), Shift(off2), Alt(-1), End] if x > 0 && off1 == -off2 => {
-                    remove_n(&mut output, 6);
-
-                    output.push(CopyClear {
-                        mul: x as u8,
-                        offset: off1,
-                    });
-                }
-                _ => {}
-            }
-        }
-    }
 
-    output
+    map_loops(nodes, copy_loop_optimizer, |body, span| {
+        let (off1, x, off2) = match body[..] {
+            // ex: "[->>>++<<<]" -> CopyClear { mul: 2, offset: 3 }
+            [Node::Ins(Alt(-1), _), Node::Ins(Shift(off1), _), Node::Ins(Alt(x), _), Node::Ins(Shift(off2), _)] => {
+                (off1, x, off2)
+            }
+            // ex: "[>>---<<+-]" -> CopyClear { mul: -3, offset: 2 }
+            [Node::Ins(Shift(off1), _), Node::Ins(Alt(x), _), Node::Ins(Shift(off2), _), Node::Ins(Alt(-1), _)] => {
+                (off1, x, off2)
+            }
+            _ => return None,
+        };
+
+        (x > 0 && off1 == -off2).then_some(Node::Ins(
+            CopyClear {
+                mul: x as u8,
+                offset: off1,
+            },
+            span,
+        ))
+    })
 }
 
 /// Remove NoOp, Alt(0), Shift(0) instructions
-fn no_op_optimizer(instructions: Vec<Instruction>) -> Vec<Instruction> {
+fn no_op_optimizer(nodes: Vec<Node>) -> Vec<Node> {
     use Instruction::*;
     let mut output = vec![];
 
-    for instruction in instructions {
-        if !matches!(instruction, Alt(0) | Shift(0)) {
-            output.push(instruction);
+    for node in nodes {
+        match node {
+            Node::Ins(Alt(0) | Shift(0), _) => {}
+            Node::Loop { body, span } => output.push(Node::Loop {
+                body: no_op_optimizer(body),
+                span,
+            }),
+            other => output.push(other),
         }
     }
 
     output
 }
 
-/// Helper to remove up to the last n instructions from a `Vec<T>`
-fn remove_n<T>(vec: &mut Vec<T>, n: usize) {
-    let final_length = vec.len().saturating_sub(n);
-    vec.truncate(final_length);
+/// Helper to rewrite loops in a tree. `replace` is given each loop's
+/// body and span, and returns the node to replace it with, if any.
+/// Loops that aren't replaced have `recurse` applied to their bodies.
+fn map_loops<R>(nodes: Vec<Node>, recurse: fn(Vec<Node>) -> Vec<Node>, replace: R) -> Vec<Node>
+where
+    R: Fn(&[Node], Span) -> Option<Node>,
+{
+    nodes
+        .into_iter()
+        .map(|node| match node {
+            Node::Loop { body, span } => match replace(&body, span) {
+                Some(replacement) => replacement,
+                None => Node::Loop {
+                    body: recurse(body),
+                    span,
+                },
+            },
+            other => other,
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Build a tree from instructions, giving each a unit `Span` matching its index
+    fn tree(instructions: Vec<Instruction>) -> Vec<Node> {
+        ir::build(
+            instructions
+                .into_iter()
+                .enumerate()
+                .map(|(i, ins)| (ins, Span { start: i, end: i + 1 }))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    fn flat(nodes: Vec<Node>) -> Vec<Instruction> {
+        ir::lower(nodes).into_iter().map(|(ins, _)| ins).collect()
+    }
+
+    #[test]
+    fn test_line_col() {
+        let src = "+\né[\n  ]";
+        assert_eq!((1, 1), Span { start: 0, end: 1 }.line_col(src));
+        assert_eq!((2, 2), Span { start: 4, end: 5 }.line_col(src));
+        assert_eq!((3, 3), Span { start: 8, end: 9 }.line_col(src));
+    }
+
+    #[test]
+    fn test_check() {
+        assert_eq!(Ok(()), check("+[>[-]<]"));
+        assert_eq!(
+            Err(CompileError::UnmatchedEnd(Span { start: 4, end: 5 })),
+            check("+[-]]")
+        );
+        assert_eq!(
+            Err(CompileError::UnmatchedLoop(Span { start: 3, end: 4 })),
+            check("[]x[+")
+        );
+    }
+
     #[test]
     fn test_contraction_optimizer() {
         use Instruction::*;
         let input = vec![Shift(1), Shift(2), Shift(3), Shift(-2)];
 
-        let output = contraction_optimizer(input);
+        let output = flat(contraction_optimizer(tree(input)));
         assert_eq!(vec![Shift(4)], output);
     }
 
@@ -257,7 +399,7 @@ mod tests {
         use Instruction::*;
         let input = vec![Loop, Alt(-1), End];
 
-        let output = clear_loop_optimizer(input);
+        let output = flat(clear_loop_optimizer(tree(input)));
         assert_eq!(vec![Clear], output);
     }
 
@@ -266,16 +408,25 @@ mod tests {
         use Instruction::*;
         let input = vec![Shift(1), Loop, Alt(-1), End, Alt(1)];
 
-        let output = clear_loop_optimizer(input);
+        let output = flat(clear_loop_optimizer(tree(input)));
         assert_eq!(vec![Shift(1), Clear, Alt(1)], output);
     }
 
+    #[test]
+    fn test_clear_loop_nested_optimizer() {
+        use Instruction::*;
+        let input = vec![Loop, Shift(1), Loop, Alt(-1), End, Shift(-1), Alt(-1), End];
+
+        let output = flat(clear_loop_optimizer(tree(input)));
+        assert_eq!(vec![Loop, Shift(1), Clear, Shift(-1), Alt(-1), End], output);
+    }
+
     #[test]
     fn test_copy_loop_optimizer_right() {
         use Instruction::*;
         let input = vec![Loop, Alt(-1), Shift(5), Alt(1), Shift(-5), End];
 
-        let output = copy_loop_optimizer(input);
+        let output = flat(copy_loop_optimizer(tree(input)));
         assert_eq!(vec![CopyClear { mul: 1, offset: 5 }], output);
     }
 
@@ -284,7 +435,7 @@ mod tests {
         use Instruction::*;
         let input = vec![Loop, Alt(-1), Shift(-3), Alt(1), Shift(3), End];
 
-        let output = copy_loop_optimizer(input);
+        let output = flat(copy_loop_optimizer(tree(input)));
         assert_eq!(vec![CopyClear { mul: 1, offset: -3 }], output);
     }
 
@@ -293,7 +444,62 @@ mod tests {
         use Instruction::*;
         let input = vec![Loop, Alt(-1), Shift(3), Alt(4), Shift(-3), End];
 
-        let output = copy_loop_optimizer(input);
+        let output = flat(copy_loop_optimizer(tree(input)));
         assert_eq!(vec![CopyClear { mul: 4, offset: 3 }], output);
     }
+
+    #[test]
+    fn test_copy_loop_nested() {
+        use Instruction::*;
+        let input = vec![
+            Loop,
+            Loop,
+            Alt(-1),
+            Shift(2),
+            Alt(1),
+            Shift(-2),
+            End,
+            Shift(1),
+            End,
+        ];
+
+        let output = flat(copy_loop_optimizer(tree(input)));
+        assert_eq!(vec![Loop, CopyClear { mul: 1, offset: 2 }, Shift(1), End], output);
+    }
+
+    #[test]
+    fn test_copy_loop_extra_instructions() {
+        use Instruction::*;
+        let input = vec![Loop, Alt(-1), Shift(2), Alt(1), Shift(-2), Out, End];
+
+        let output = flat(copy_loop_optimizer(tree(input.clone())));
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut p = compile("+[>.<-]");
+        p.append(compile(">[.-]"));
+
+        assert_eq!(compile("+[>.<-]>[.-]").instructions, p.instructions);
+        assert_eq!(match_loops(&p.instructions), Some(p.loop_map));
+        assert_eq!(None, p.source_map);
+    }
+
+    #[test]
+    fn test_source_map() {
+        use Instruction::*;
+        let p = compile("+ +>\n[-]x.");
+
+        assert_eq!(vec![Alt(2), Shift(1), Clear, Out], p.instructions);
+        assert_eq!(
+            Some(vec![
+                Span { start: 0, end: 3 },
+                Span { start: 3, end: 4 },
+                Span { start: 5, end: 8 },
+                Span { start: 9, end: 10 },
+            ]),
+            p.source_map
+        );
+    }
 }
@@ -0,0 +1,147 @@
+//! Brainfuck source `Coverage`. Maps the instruction counts
+//! of a `Profile` back onto the commands of the source, to show
+//! which commands ran at least once and which are dead code.
+
+use std::fmt::Write;
+
+use crate::{compiler::Program, instruction::Instruction, profiler::Profile};
+
+/// Execution counts for each command of a brainfuck source.
+/// Commands the optimizer removed entirely, like `+-`, aren't counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage<'a> {
+    src: &'a str,
+    /// Times the command at each byte ran, `None` for anything that isn't a counted command
+    hits: Vec<Option<u64>>,
+}
+
+impl<'a> Coverage<'a> {
+    /// Map a `Profile` of `program` onto the source it was compiled from.
+    /// Returns `None` if the program has no source map.
+    pub fn new(src: &'a str, program: &Program, profile: &Profile) -> Option<Self> {
+        let source_map = program.source_map.as_ref()?;
+        let mut hits = vec![None; src.len()];
+
+        for (span, &count) in source_map.iter().zip(profile.counts()) {
+            for (pos, c) in src[span.start..span.end].char_indices() {
+                if Instruction::try_from(c).is_ok() {
+                    let hit = &mut hits[span.start + pos];
+                    *hit = Some(hit.unwrap_or(0).max(count));
+                }
+            }
+        }
+
+        Some(Coverage { src, hits })
+    }
+
+    /// Number of commands that ran, and the number of commands counted
+    pub fn summary(&self) -> (usize, usize) {
+        let commands = self.hits.iter().flatten();
+        (
+            commands.clone().filter(|&&hits| hits > 0).count(),
+            commands.count(),
+        )
+    }
+
+    /// Copy of the source with the execution count of each line,
+    /// and a `^` under each command on the line that never ran.
+    pub fn annotate(&self) -> String {
+        let mut out = String::new();
+
+        for (_, start, line) in self.lines() {
+            let hits = &self.hits[start..start + line.len()];
+            let count = match line_hits(hits) {
+                None => "-".to_string(),
+                Some(0) => "#####".to_string(),
+                Some(count) => count.to_string(),
+            };
+            writeln!(out, "{count:>9} | {line}").unwrap();
+
+            if hits.contains(&Some(0)) {
+                // keep tabs, so the markers line up with the line above
+                let markers: String = line
+                    .char_indices()
+                    .map(|(pos, c)| match (hits[pos], c) {
+                        (Some(0), _) => '^',
+                        (_, '\t') => '\t',
+                        _ => ' ',
+                    })
+                    .collect();
+                writeln!(out, "{:>9} | {}", "", markers.trim_end()).unwrap();
+            }
+        }
+
+        out
+    }
+
+    /// lcov tracefile for the source file `name`, counting each line
+    /// that has commands by its most executed command
+    pub fn lcov(&self, name: &str) -> String {
+        let mut out = format!("TN:\nSF:{name}\n");
+        let (mut found, mut hit) = (0, 0);
+
+        for (number, start, line) in self.lines() {
+            if let Some(count) = line_hits(&self.hits[start..start + line.len()]) {
+                writeln!(out, "DA:{number},{count}").unwrap();
+                found += 1;
+                hit += (count > 0) as usize;
+            }
+        }
+
+        writeln!(out, "LF:{found}\nLH:{hit}\nend_of_record").unwrap();
+        out
+    }
+
+    /// Each line of the source, with its 1-based line number and starting byte
+    fn lines(&self) -> impl Iterator<Item = (usize, usize, &'a str)> {
+        let src = self.src;
+        src.split('\n').enumerate().map(move |(i, line)| {
+            let start = line.as_ptr() as usize - src.as_ptr() as usize;
+            (i + 1, start, line.strip_suffix('\r').unwrap_or(line))
+        })
+    }
+}
+
+/// Execution count of a line, from the hits of its bytes.
+/// `None` if the line has no counted commands.
+fn line_hits(hits: &[Option<u64>]) -> Option<u64> {
+    hits.iter().flatten().copied().max()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::compile, io::MemoryIO, vm::VM};
+
+    use super::*;
+
+    fn coverage(src: &str) -> Coverage<'_> {
+        let program = compile(src);
+        let mut profile = Profile::new(&program);
+        let mut vm = VM::new_with_io(program.clone(), MemoryIO::new(""));
+
+        profile.run(&mut vm, u64::MAX).unwrap();
+        Coverage::new(src, &program, &profile).unwrap()
+    }
+
+    #[test]
+    fn test_annotate() {
+        // the second loop is never entered
+        let coverage = coverage("++[>+<-] set\n>>[.[-]]\n");
+
+        assert_eq!((11, 16), coverage.summary());
+        assert_eq!(
+            "        1 | ++[>+<-] set\n        1 | >>[.[-]]\n          |    ^^^^^\n        - | \n",
+            coverage.annotate()
+        );
+    }
+
+    #[test]
+    fn test_lcov() {
+        let coverage = coverage(">\n[\n.\n]\ncomment");
+
+        assert_eq!(
+            "TN:\nSF:test.bf\nDA:1,1\nDA:2,1\nDA:3,0\nDA:4,0\nLF:4\nLH:2\nend_of_record\n",
+            coverage.lcov("test.bf")
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! The brainfuck `Debugger`. Steps a `VM` through its
+//! program from a command prompt, with the breakpoints and
+//! watchpoints of a `Watcher`, and the source each instruction
+//! came from. Keeps a `History` of what each instruction changed,
+//! so execution can also be stepped backwards.
+
+use std::io::{self, BufRead, Write};
+
+use crate::{
+    history::{CellWrite, History},
+    io::InputOutput,
+    vm::VM,
+    watch::{Breakpoint, Stop, Watcher, Watchpoint},
+};
+
+/// Instructions shown on either side of the current one by `list`
+const LIST_CONTEXT: usize = 3;
+
+/// Instructions that can be stepped back through
+const HISTORY_LIMIT: usize = 1_000_000;
+
+const HELP: &str = "\
+commands:
+  s, step [n]          run n instructions, 1 by default
+  c, continue          run until a breakpoint, a watchpoint, or the end of the program
+  back [n]             step back n instructions, 1 by default
+  rc, reverse-continue step back until a breakpoint, or to before a watchpoint was hit
+  b, break [ip [if <condition>]]
+                       set a breakpoint on instruction ip, or list breakpoints
+  d, delete <ip>       remove the breakpoint on instruction ip
+  w, watch [<watchpoint>]
+                       set a watchpoint, or list watchpoints
+  unwatch <n>          remove watchpoint n
+  l, list              show the instructions around the current one
+  p, print             show the memory pointer and memory
+  q, quit              stop debugging
+  h, help              show this message
+an empty line steps one instruction
+
+watchpoints: `3` stops on writes to cell 3, `3 == 10` when cell 3 is set to 10,
+and `ptr 5..8` when the pointer moves into cells 5 to 7
+conditions compare `ptr`, `cell` (the current cell), `mem[<cell>]` and numbers
+with == != < <= > >=, joined with &&, like `mem[3] == 10 && ptr > 5`";
+
+/// Interactive debugger for a `VM`
+pub struct Debugger<IO: InputOutput> {
+    vm: VM<IO>,
+    /// Source the program was compiled from, to show instructions in context
+    src: Option<String>,
+    /// Breakpoints and watchpoints to stop at
+    watcher: Watcher,
+    /// Undo log of the instructions run
+    history: History,
+}
+
+impl<IO: InputOutput> Debugger<IO> {
+    /// Create a new debugger for the given VM.
+    /// Pass the program's source, if known, to show where instructions came from.
+    pub fn new(vm: VM<IO>, src: Option<String>) -> Self {
+        Debugger {
+            vm,
+            src,
+            watcher: Watcher::new(),
+            history: History::new(HISTORY_LIMIT),
+        }
+    }
+
+    /// Stop before running instruction `breakpoint.ip`
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.watcher.add_breakpoint(breakpoint);
+    }
+
+    /// Stop after an instruction hits `watchpoint`
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watcher.add_watchpoint(watchpoint);
+    }
+
+    /// The VM being debugged
+    pub fn vm(&self) -> &VM<IO> {
+        &self.vm
+    }
+
+    /// Read and run commands until `quit` or the end of `commands`
+    pub fn repl(&mut self, mut commands: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        self.location(&mut out)?;
+
+        loop {
+            write!(out, "(bfrs) ")?;
+            out.flush()?;
+
+            let mut line = String::new();
+            if commands.read_line(&mut line)? == 0 || !self.command(line.trim(), &mut out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Run a single command, returning `false` once debugging should stop
+    pub fn command(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let (command, rest) = match line.split_once(char::is_whitespace) {
+            Some((command, rest)) => (command, rest.trim()),
+            None if line.is_empty() => ("step", ""),
+            None => (line, ""),
+        };
+        let arg = (!rest.is_empty()).then(|| rest.parse::<usize>());
+
+        match (command, arg) {
+            ("s" | "step", None) => self.step(1, out)?,
+            ("s" | "step", Some(Ok(count))) => self.step(count as u64, out)?,
+            ("c" | "continue", None) => self.resume(out)?,
+            ("back", None) => self.back(1, out)?,
+            ("back", Some(Ok(count))) => self.back(count, out)?,
+            ("rc" | "reverse-continue", None) => self.reverse(out)?,
+            ("b" | "break", None) => {
+                for breakpoint in self.watcher.breakpoints() {
+                    writeln!(out, "breakpoint on {breakpoint}")?;
+                }
+            }
+            ("b" | "break", Some(_)) => match rest.parse::<Breakpoint>() {
+                Ok(breakpoint) => {
+                    writeln!(out, "breakpoint set on {breakpoint}")?;
+                    self.watcher.add_breakpoint(breakpoint);
+                }
+                Err(err) => writeln!(out, "{err}")?,
+            },
+            ("d" | "delete", Some(Ok(ip))) => match self.watcher.remove_breakpoint(ip) {
+                true => writeln!(out, "breakpoint removed from [{ip}]")?,
+                false => writeln!(out, "no breakpoint on [{ip}]")?,
+            },
+            ("w" | "watch", None) => {
+                for (i, watchpoint) in self.watcher.watchpoints().iter().enumerate() {
+                    writeln!(out, "watchpoint {i}: {watchpoint}")?;
+                }
+            }
+            ("w" | "watch", Some(_)) => match rest.parse::<Watchpoint>() {
+                Ok(watchpoint) => {
+                    writeln!(
+                        out,
+                        "watchpoint {}: {watchpoint}",
+                        self.watcher.watchpoints().len()
+                    )?;
+                    self.watcher.add_watchpoint(watchpoint);
+                }
+                Err(err) => writeln!(out, "{err}")?,
+            },
+            ("unwatch", Some(Ok(index))) => match self.watcher.remove_watchpoint(index) {
+                Some(watchpoint) => writeln!(out, "watchpoint {index} removed: {watchpoint}")?,
+                None => writeln!(out, "no watchpoint {index}")?,
+            },
+            ("l" | "list", None) => self.list(out)?,
+            ("p" | "print", None) => writeln!(out, "{}", self.vm)?,
+            ("q" | "quit", None) => return Ok(false),
+            ("h" | "help", None) => writeln!(out, "{HELP}")?,
+            (_, Some(Err(_))) => writeln!(out, "invalid number in `{line}`")?,
+            _ => writeln!(out, "unknown command `{line}`, try `help`")?,
+        }
+
+        Ok(true)
+    }
+
+    /// Run `count` instructions, stopping early at the end of the program
+    fn step(&mut self, count: u64, out: &mut impl Write) -> io::Result<()> {
+        for _ in 0..count {
+            if self.vm.is_finished() {
+                break;
+            }
+
+            if let Err(err) = self.history.step(&mut self.vm) {
+                writeln!(out, "program error: {err}")?;
+                break;
+            }
+        }
+
+        self.location(out)
+    }
+
+    /// Run until a breakpoint, a watchpoint, or the end of the program
+    fn resume(&mut self, out: &mut impl Write) -> io::Result<()> {
+        // the VM doesn't run what was stepped back through again, so
+        // replay it first, checking the watcher after each instruction
+        let mut stop = None;
+        while stop.is_none() && self.history.can_replay() {
+            let ptr = self.vm.ptr();
+            match self.history.step(&mut self.vm) {
+                Ok(writes) => stop = self.watcher.check(&self.vm, writes, ptr),
+                Err(err) => {
+                    writeln!(out, "program error: {err}")?;
+                    return self.location(out);
+                }
+            }
+        }
+
+        // then run the VM, with the watcher stopping it as an observer.
+        // At least one instruction runs, so resuming from a breakpoint moves past it
+        if stop.is_none() && !self.vm.is_finished() {
+            let mut observers = (&mut self.history, &mut self.watcher);
+            stop = match self.vm.run_observed(u64::MAX, &mut observers) {
+                Ok(()) => self.watcher.stopped(),
+                Err(err) => {
+                    writeln!(out, "program error: {err}")?;
+                    None
+                }
+            };
+        }
+
+        if let Some(Stop::Watchpoint(index)) = stop {
+            let writes = self.history.last_writes();
+            let hit = hit(index, &self.watcher.watchpoints()[index], writes, self.vm.ptr());
+            writeln!(out, "{hit}")?;
+        }
+
+        self.location(out)
+    }
+
+    /// Step back `count` instructions, stopping early at the start of the history
+    fn back(&mut self, count: usize, out: &mut impl Write) -> io::Result<()> {
+        for _ in 0..count {
+            if self.history.back(&mut self.vm).is_none() {
+                writeln!(out, "no more history to step back through")?;
+                break;
+            }
+        }
+
+        self.location(out)
+    }
+
+    /// Step back until a breakpoint, the start of the history,
+    /// or to just before an instruction that hit a watchpoint
+    fn reverse(&mut self, out: &mut impl Write) -> io::Result<()> {
+        loop {
+            let ptr = self.vm.ptr();
+            let Some(writes) = self.history.back(&mut self.vm) else {
+                writeln!(out, "no more history to step back through")?;
+                break;
+            };
+
+            // the undone instruction moved the pointer from where it is now to `ptr`
+            if let Some(index) = self.watcher.watchpoint(writes, self.vm.ptr(), ptr) {
+                let hit = hit(index, &self.watcher.watchpoints()[index], writes, ptr);
+                writeln!(out, "{hit}")?;
+                break;
+            }
+
+            if self.watcher.at_breakpoint(&self.vm) {
+                break;
+            }
+        }
+
+        self.location(out)
+    }
+
+    /// Show the instruction that runs next
+    fn location(&self, out: &mut impl Write) -> io::Result<()> {
+        match self.vm.is_finished() {
+            true => writeln!(out, "program finished after {} steps", self.vm.steps()),
+            false => writeln!(out, "{}", self.describe(self.vm.ip())),
+        }
+    }
+
+    /// Show the instructions around the current one
+    fn list(&self, out: &mut impl Write) -> io::Result<()> {
+        let len = self.vm.program().instructions.len();
+        let ip = self.vm.ip();
+        let start = ip.saturating_sub(LIST_CONTEXT);
+        let end = (ip + LIST_CONTEXT + 1).min(len);
+
+        for i in start..end {
+            let marker = match (i == ip, self.watcher.has_breakpoint(i)) {
+                (true, _) => "=>",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            writeln!(out, "{marker} {}", self.describe(i))?;
+        }
+
+        Ok(())
+    }
+
+    /// Describe instruction `ip`, with its source if known
+    fn describe(&self, ip: usize) -> String {
+        let program = self.vm.program();
+        let mut text = format!("[{ip}] {:?}", program.instructions[ip]);
+
+        let span = program.source_map.as_ref().map(|map| map[ip]);
+        if let (Some(span), Some(src)) = (span, &self.src) {
+            let (line, col) = span.line_col(src);
+            text.push_str(&format!(" at {line}:{col} `{}`", &src[span.start..span.end]));
+        }
+
+        text
+    }
+}
+
+/// Describe a hit of watchpoint `index`, given what the instruction
+/// that hit it wrote, and where it left the memory pointer
+fn hit(index: usize, watchpoint: &Watchpoint, writes: &[CellWrite], ptr: usize) -> String {
+    let cell = match watchpoint {
+        Watchpoint::Write(cell) | Watchpoint::Value { cell, .. } => *cell,
+        Watchpoint::Pointer(_) => return format!("watchpoint {index} hit: {watchpoint}, ptr is {ptr}"),
+    };
+
+    match writes.iter().find(|write| write.cell == cell) {
+        Some(write) => format!(
+            "watchpoint {index} hit: {watchpoint}, {} -> {}",
+            write.before, write.after
+        ),
+        None => format!("watchpoint {index} hit: {watchpoint}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::compile, io::MemoryIO};
+
+    use super::*;
+
+    fn debug(src: &str, input: &str, commands: &str) -> (Debugger<MemoryIO>, String) {
+        let vm = VM::new_with_io(compile(src), MemoryIO::new(input));
+        let mut debugger = Debugger::new(vm, Some(src.to_string()));
+
+        let mut out = Vec::new();
+        debugger.repl(commands.as_bytes(), &mut out).unwrap();
+        (debugger, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_step() {
+        let (debugger, out) = debug("++\n>,.", "a", "step\n\ns 2\n");
+
+        assert!(out.starts_with("[0] Alt(2) at 1:1 `++`\n"));
+        assert!(out.contains("[1] Shift(1) at 2:1 `>`\n"));
+        assert!(out.contains("[2] In at 2:2 `,`\n"));
+        assert!(out.ends_with("program finished after 4 steps\n(bfrs) "));
+        assert_eq!(b"a", debugger.vm().io().output());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // the breakpoint is inside the loop, so it's hit on every iteration
+        let (debugger, out) = debug("+++[>+.<-]", "", "b 3\nc\nc\nd 3\nc\n");
+
+        assert!(out.contains("breakpoint set on [3]"));
+        assert_eq!(2, out.matches("[3] Alt(1) at 1:6 `+`").count());
+        assert!(out.contains("breakpoint removed from [3]"));
+        assert!(out.contains("program finished"));
+        assert_eq!(b"\x01\x02\x03", debugger.vm().io().output());
+    }
+
+    #[test]
+    fn test_reverse() {
+        // cell 1 counts up to 3, and is printed each time
+        let (debugger, out) = debug(
+            "+++[>+.<-]",
+            "",
+            "w 1\nc\nc\nc\nback 2\nrc\nrc\nrc\nunwatch 0\nc\n",
+        );
+
+        assert!(out.contains("watchpoint 0: write to cell 1"));
+        // going back 2 from the third write undoes it without stopping,
+        // so reverse-continue stops before the second and the first
+        assert_eq!(2, out.matches("write to cell 1, 0 -> 1").count());
+        assert_eq!(2, out.matches("write to cell 1, 1 -> 2").count());
+        assert_eq!(1, out.matches("write to cell 1, 2 -> 3").count());
+        assert_eq!(2, out.matches("[3] Alt(1) at 1:6 `+`").count());
+        assert!(out.contains("no more history to step back through"));
+        assert!(out.contains("watchpoint 0 removed"));
+        assert!(out.ends_with("program finished after 20 steps\n(bfrs) "));
+        // replayed instructions don't write their output again
+        assert_eq!(b"\x01\x02\x03", debugger.vm().io().output());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let (debugger, out) = debug(
+            "+++[>+.<-]",
+            "",
+            "b 4 if mem[1] == 2\nw 0 == 1\nc\nc\nb\nw\nb 3 if foo > 1\nw 40000\nc\n",
+        );
+
+        assert!(out.contains("breakpoint set on [4] if mem[1] == 2"));
+        // the breakpoint only holds in the second iteration
+        assert_eq!(1, out.matches("[4] Out at 1:7 `.`").count());
+        assert!(out.contains("watchpoint 0 hit: cell 0 == 1, 2 -> 1\n[7] End"));
+        assert!(out.contains("breakpoint on [4] if mem[1] == 2\n(bfrs) watchpoint 0: cell 0 == 1\n"));
+        assert!(out.contains("invalid operand `foo`"));
+        assert!(out.contains("cell 40000 is outside of memory"));
+        assert!(out.contains("program finished"));
+        assert_eq!(b"\x01\x02\x03", debugger.vm().io().output());
+    }
+
+    #[test]
+    fn test_commands() {
+        let (_, out) = debug("+[-]", "", "l\nstep x\nfoo\nq\ns\n");
+
+        assert!(out.contains("=> [0] Alt(1)"));
+        assert!(out.contains("   [1] Clear"));
+        assert!(out.contains("invalid number in `step x`"));
+        assert!(out.contains("unknown command `foo`"));
+        // nothing runs after quitting
+        assert!(!out.contains("program finished"));
+    }
+}
@@ -0,0 +1,302 @@
+//! The pre-decoded brainfuck `DecodedVM`. An alternative
+//! execution engine that translates a `Program` into a single
+//! instruction stream with jump targets stored inline, so the
+//! dispatch loop never touches a separate loop map.
+
+use crate::{
+    compiler::Program,
+    instruction::Instruction,
+    io::{InputOutput, StdIO, StepCounter},
+    vm::{VMError, MEM},
+};
+
+/// Pre-decoded VM instruction. Mirrors `Instruction`,
+/// with each loop's jump target stored in its `Loop` and `End`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Shift(isize),
+    Alt(i16),
+    Out,
+    In,
+    /// Jump to the given index (one past the matching End) if the cell is zero
+    Loop(usize),
+    /// Jump to the given index (one past the matching Loop) if the cell is nonzero
+    End(usize),
+    Clear,
+    CopyClear {
+        mul: u8,
+        offset: isize,
+    },
+}
+
+/// Brainfuck VM executing pre-decoded instructions.
+#[derive(Debug, Clone)]
+pub struct DecodedVM<IO: InputOutput> {
+    /// Decoded Brainfuck program
+    ops: Vec<Op>,
+
+    /// Program Memory
+    data: Box<[u8; MEM]>,
+
+    /// Memory pointer
+    ptr: usize,
+
+    /// InputOutput implementation
+    io: IO,
+
+    /// Instruction to run next, kept between runs
+    ip: usize,
+
+    /// Number of instructions executed
+    steps: u64,
+
+    /// Counter kept at `steps` for the `IO`, set by `share_steps`
+    shared_steps: Option<StepCounter>,
+}
+
+impl DecodedVM<StdIO> {
+    /// Create a new decoded VM to execute the given Program.
+    /// Configured to use Stdin and Stdout.
+    pub fn new(program: Program) -> Self {
+        DecodedVM::new_with_io(program, StdIO::new())
+    }
+}
+
+impl<IO: InputOutput> DecodedVM<IO> {
+    /// Create a new decoded VM to execute the given Program.
+    /// Configured to use the given `IO` for input-output.
+    pub fn new_with_io(program: Program, io: IO) -> Self {
+        DecodedVM {
+            ops: decode(&program),
+            data: Box::new([0; MEM]),
+            ptr: 0,
+            io,
+            ip: 0,
+            steps: 0,
+            shared_steps: None,
+        }
+    }
+
+    /// Keep `counter` at the number of steps run whenever the
+    /// VM reads or writes, so the `IO` can tell when it's called
+    pub fn share_steps(&mut self, counter: StepCounter) {
+        counter.set(self.steps);
+        self.shared_steps = Some(counter);
+    }
+
+    /// Runs the VM until the program ends or fails.
+    /// Reading past the end of input stores 0 in the current cell.
+    ///
+    /// The VM stops on the instruction that failed, and the next
+    /// call to `run` retries it, so an `InputOutput` returning
+    /// `ErrorKind::WouldBlock` pauses the program rather than ending it.
+    pub fn run(&mut self) -> Result<(), VMError> {
+        // counted locally so they can stay in registers, and
+        // stored back whenever the IO or the caller can see them
+        let mut instruction_ptr = self.ip;
+        let mut steps = self.steps;
+
+        while instruction_ptr < self.ops.len() {
+            // current instruction to execute, jumps below
+            // overwrite the already-advanced instruction_ptr
+            let op = &self.ops[instruction_ptr];
+            instruction_ptr += 1;
+
+            // the current cell, failing if the pointer has left memory
+            macro_rules! cell {
+                () => {
+                    match self.data.get_mut(self.ptr) {
+                        Some(cell) => cell,
+                        None => return Err(self.out_of_bounds(instruction_ptr - 1, steps, self.ptr)),
+                    }
+                };
+            }
+
+            match *op {
+                Op::Shift(count) => {
+                    self.ptr = self.ptr.wrapping_add_signed(count);
+                }
+                Op::Alt(amount) => {
+                    let cell = cell!();
+                    *cell = match amount >= 0 {
+                        true => cell.wrapping_add(amount as u8),
+                        false => cell.wrapping_sub(-amount as u8),
+                    };
+                }
+                Op::Out => {
+                    let byte = *cell!();
+                    self.ip = instruction_ptr - 1;
+                    self.steps = steps;
+                    self.publish_steps();
+                    self.io.print(byte)?;
+                }
+                Op::In => {
+                    cell!();
+                    self.ip = instruction_ptr - 1;
+                    self.steps = steps;
+                    self.publish_steps();
+                    self.data[self.ptr] = self.io.getch()?.unwrap_or(0);
+                }
+                Op::Loop(target) => {
+                    if *cell!() == 0u8 {
+                        instruction_ptr = target;
+                    }
+                }
+                Op::End(target) => {
+                    if *cell!() != 0u8 {
+                        instruction_ptr = target;
+                    }
+                }
+                Op::Clear => {
+                    *cell!() = 0u8;
+                }
+                Op::CopyClear { mul, offset } => {
+                    // the target only has to be in memory when the current cell is nonzero, like in `VM`
+                    let value = std::mem::take(cell!());
+                    if value != 0 {
+                        let target = self.ptr.wrapping_add_signed(offset);
+                        match self.data.get_mut(target) {
+                            Some(cell) => *cell = cell.wrapping_add(value.wrapping_mul(mul)),
+                            None => {
+                                self.data[self.ptr] = value;
+                                return Err(self.out_of_bounds(instruction_ptr - 1, steps, target));
+                            }
+                        }
+                    }
+                }
+            }
+
+            steps += 1;
+        }
+
+        self.ip = instruction_ptr;
+        self.steps = steps;
+        Ok(self.io.flush()?)
+    }
+
+    /// Update the shared step counter, if there is one
+    #[inline]
+    fn publish_steps(&self) {
+        if let Some(counter) = &self.shared_steps {
+            counter.set(self.steps);
+        }
+    }
+
+    /// Stop on instruction `ip`, which used the pointer `ptr` outside of memory.
+    /// Kept out of line, so the check doesn't slow down the dispatch loop.
+    #[cold]
+    fn out_of_bounds(&mut self, ip: usize, steps: u64, ptr: usize) -> VMError {
+        self.ip = ip;
+        self.steps = steps;
+        VMError::PointerOutOfBounds {
+            ip,
+            ptr: ptr as isize,
+        }
+    }
+
+    /// Whether the program has run to completion
+    pub fn is_finished(&self) -> bool {
+        self.ip >= self.ops.len()
+    }
+
+    /// Number of instructions executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// The VM's `InputOutput` implementation
+    pub fn io(&self) -> &IO {
+        &self.io
+    }
+
+    /// The VM's `InputOutput` implementation, mutably
+    pub fn io_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+
+    /// Unwrap the VM, returning its `InputOutput` implementation
+    pub fn into_io(self) -> IO {
+        self.io
+    }
+}
+
+/// Translate a `Program` into `Op`s, resolving each
+/// loop's jump target from the program's loop map.
+fn decode(program: &Program) -> Vec<Op> {
+    let target = |ptr: usize| program.loop_map[ptr] + 1;
+
+    program
+        .instructions
+        .iter()
+        .enumerate()
+        .map(|(ptr, ins)| match *ins {
+            Instruction::Shift(count) => Op::Shift(count),
+            Instruction::Alt(amount) => Op::Alt(amount),
+            Instruction::Out => Op::Out,
+            Instruction::In => Op::In,
+            Instruction::Loop => Op::Loop(target(ptr)),
+            Instruction::End => Op::End(target(ptr)),
+            Instruction::Clear => Op::Clear,
+            Instruction::CopyClear { mul, offset } => Op::CopyClear { mul, offset },
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::compile, io::MemoryIO, vm::VM};
+
+    use super::*;
+
+    #[test]
+    fn test_decode_jump_targets() {
+        let ops = decode(&compile("+[>.<-]"));
+
+        assert_eq!(Op::Loop(7), ops[1]);
+        assert_eq!(Op::End(2), ops[6]);
+    }
+
+    #[test]
+    fn test_pointer_out_of_bounds() {
+        let mut i = DecodedVM::new_with_io(compile("+.<."), MemoryIO::new(""));
+        let err = i.run().unwrap_err();
+
+        assert!(matches!(err, VMError::PointerOutOfBounds { ip: 3, ptr: -1 }));
+        assert!(!i.is_finished());
+
+        let mut i = DecodedVM::new_with_io(compile("+[-<+>]"), MemoryIO::new(""));
+        let err = i.run().unwrap_err();
+        assert!(matches!(err, VMError::PointerOutOfBounds { ip: 1, ptr: -1 }));
+    }
+
+    #[test]
+    fn test_hello_world() {
+        let src = include_str!("../samples/helloworld.bf");
+        let p = compile(src);
+        let mut i = DecodedVM::new_with_io(p, MemoryIO::new(""));
+
+        i.run().unwrap();
+        assert_eq!("Hello World!\n".as_bytes(), i.io().output());
+    }
+
+    #[test]
+    fn test_matches_vm() {
+        let samples = [
+            include_str!("../samples/666.bf"),
+            include_str!("../samples/sierpinski.bf"),
+            include_str!("../samples/fib11.bf"),
+            // copy loops to either side, on input
+            ">>,[->++>+<<]>[-<<+>>]<<<,[->>>>+++<<<<]>>>>.<<<.>>.",
+        ];
+
+        for src in samples {
+            let mut vm = VM::new_with_io(compile(src), MemoryIO::new("\x05\x02"));
+            let mut decoded = DecodedVM::new_with_io(compile(src), MemoryIO::new("\x05\x02"));
+
+            vm.run().unwrap();
+            decoded.run().unwrap();
+            assert_eq!(vm.io().output(), decoded.io().output());
+            assert_eq!(vm.data(), &decoded.data[..]);
+        }
+    }
+}
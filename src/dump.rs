@@ -0,0 +1,154 @@
+//! Dumps of a brainfuck VM's memory tape. `hex` shows the whole
+//! tape like `hexdump -C`, with offset, hex and ASCII columns and
+//! repeated rows collapsed to `*`, `window` shows the rows around
+//! the pointer, and `raw` writes the tape's bytes as they are.
+
+use std::{fmt::Write as _, io};
+
+/// Cells shown on each row
+pub const ROW: usize = 16;
+
+/// The whole tape, like `hexdump -C`. A run of rows repeating the one
+/// before it is shown as a single `*`, and the last line is the tape's length.
+pub fn hex(data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut previous: Option<&[u8]> = None;
+    let mut repeated = false;
+
+    for (i, row) in data.chunks(ROW).enumerate() {
+        // a short last row is always shown, like hexdump does
+        if previous == Some(row) && row.len() == ROW {
+            if !repeated {
+                out.push_str("*\n");
+                repeated = true;
+            }
+            continue;
+        }
+
+        push_row(&mut out, i * ROW, row, None);
+        previous = Some(row);
+        repeated = false;
+    }
+
+    writeln!(out, "{:08x}", data.len()).unwrap();
+    out
+}
+
+/// The `rows` rows either side of the row holding cell `ptr`,
+/// with the pointer's cell bracketed like `[41]`. A pointer
+/// outside of `data` shows the rows at the end of it instead.
+pub fn window(data: &[u8], ptr: usize, rows: usize) -> String {
+    let row = (ptr / ROW).min(data.len().saturating_sub(1) / ROW);
+    let start = row.saturating_sub(rows) * ROW;
+    let end = row
+        .saturating_add(rows)
+        .saturating_add(1)
+        .saturating_mul(ROW)
+        .min(data.len());
+
+    let mut out = String::new();
+    for offset in (start..end).step_by(ROW) {
+        let cells = &data[offset..(offset + ROW).min(end)];
+        push_row(
+            &mut out,
+            offset,
+            cells,
+            ptr.checked_sub(offset).filter(|&i| i < cells.len()),
+        );
+    }
+
+    out
+}
+
+/// The tape's bytes as they are, one byte per cell from cell 0
+pub fn raw(data: &[u8], out: &mut impl io::Write) -> io::Result<()> {
+    out.write_all(data)?;
+    out.flush()
+}
+
+/// Push a row of up to `ROW` cells starting at `offset`,
+/// bracketing the cell at index `mark` of the row
+fn push_row(out: &mut String, offset: usize, cells: &[u8], mark: Option<usize>) {
+    write!(out, "{offset:08x} ").unwrap();
+    for i in 0..ROW {
+        if i == ROW / 2 {
+            out.push(' ');
+        }
+        out.push(separator(i, mark));
+        match cells.get(i) {
+            Some(cell) => write!(out, "{cell:02x}").unwrap(),
+            None => out.push_str("  "),
+        }
+    }
+    out.push(separator(ROW, mark));
+
+    out.push_str(" |");
+    out.extend(cells.iter().map(|&cell| match cell {
+        b' '..=b'~' => cell as char,
+        _ => '.',
+    }));
+    out.push_str("|\n");
+}
+
+/// Separator before the cell at index `i` of a row
+fn separator(i: usize, mark: Option<usize>) -> char {
+    match mark {
+        Some(mark) if mark == i => '[',
+        Some(mark) if mark + 1 == i => ']',
+        _ => ' ',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        let mut data = vec![0; 64];
+        data[..5].copy_from_slice(b"Hello");
+        data[60] = 0xff;
+
+        assert_eq!(
+            "\
+00000000  48 65 6c 6c 6f 00 00 00  00 00 00 00 00 00 00 00  |Hello...........|
+00000010  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|
+*
+00000030  00 00 00 00 00 00 00 00  00 00 00 00 ff 00 00 00  |................|
+00000040
+",
+            hex(&data)
+        );
+        assert_eq!(
+            format!("00000000  61 62 63{}  |abc|\n00000003\n", " ".repeat(3 * 13 + 1)),
+            hex(b"abc")
+        );
+    }
+
+    #[test]
+    fn test_window() {
+        let data: Vec<u8> = (0..40).collect();
+
+        assert_eq!(
+            "\
+00000000  00 01 02 03 04 05 06 07  08 09 0a 0b 0c 0d 0e[0f] |................|
+00000010  10 11 12 13 14 15 16 17  18 19 1a 1b 1c 1d 1e 1f  |................|
+",
+            window(&data, 15, 1)
+        );
+        assert_eq!(
+            "00000020  20 21[22]23 24 25 26 27                           | !\"#$%&'|\n",
+            window(&data, 34, 0)
+        );
+
+        // a pointer outside of memory, or a huge window, stops at the end
+        let last = "00000020  20 21 22 23 24 25 26 27                           | !\"#$%&'|\n";
+        assert_eq!(last, window(&data, usize::MAX, 0));
+        assert_eq!(last, window(&data, 40, 0));
+        assert_eq!(3, window(&data, 0, usize::MAX).lines().count());
+
+        let mut out = Vec::new();
+        raw(&data, &mut out).unwrap();
+        assert_eq!(data, out);
+    }
+}
@@ -0,0 +1,161 @@
+//! The brainfuck source formatter.
+//! Rewrites source code as either minified code or pretty
+//! code indented by loop depth. Uses the compiler's parser,
+//! so anything it skips is treated as a comment.
+
+use crate::{compiler::parse, instruction::Instruction};
+
+/// Indentation used for each level of loop nesting
+const INDENT: &str = "    ";
+
+/// Minify brainfuck source, keeping only the eight commands
+pub fn minify(src: &str) -> String {
+    parse(src)
+        .iter()
+        .map(|(_, span)| &src[span.start..span.end])
+        .collect()
+}
+
+/// Pretty-print brainfuck source. Each loop bracket gets its own line,
+/// and the commands between brackets are indented by loop depth.
+/// If `comments` is set, comments are kept, otherwise they are dropped.
+pub fn pretty(src: &str, comments: bool) -> String {
+    let mut printer = Printer::default();
+    let mut last = 0;
+
+    for (ins, span) in parse(src) {
+        if comments {
+            printer.comment(&src[last..span.start]);
+        }
+
+        let code = &src[span.start..span.end];
+        match ins {
+            Instruction::Loop => {
+                printer.flush();
+                printer.push_line(code);
+                printer.depth += 1;
+            }
+            Instruction::End => {
+                printer.flush();
+                // tolerate unbalanced loops, the formatter doesn't validate programs
+                printer.depth = printer.depth.saturating_sub(1);
+                printer.push_line(code);
+            }
+            _ => printer.code(code),
+        }
+
+        last = span.end;
+    }
+
+    if comments {
+        printer.comment(&src[last..]);
+    }
+
+    printer.flush();
+    printer.out
+}
+
+/// Line-based output buffer for `pretty`
+#[derive(Default)]
+struct Printer {
+    /// Formatted output so far
+    out: String,
+    /// Current line, not yet indented
+    line: String,
+    /// Whether the current line ends with comment text
+    in_comment: bool,
+    /// Current loop depth
+    depth: usize,
+}
+
+impl Printer {
+    /// Append commands to the current line
+    fn code(&mut self, code: &str) {
+        if self.in_comment {
+            self.line.push(' ');
+            self.in_comment = false;
+        }
+
+        self.line.push_str(code);
+    }
+
+    /// Append comment text, keeping its line breaks
+    /// but normalizing the whitespace around them
+    fn comment(&mut self, text: &str) {
+        for (i, piece) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.flush();
+            }
+
+            let piece = piece.trim();
+            if !piece.is_empty() {
+                if !self.line.is_empty() {
+                    self.line.push(' ');
+                }
+
+                self.line.push_str(piece);
+                self.in_comment = true;
+            }
+        }
+    }
+
+    /// Finish the current line, if it has any content
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.push_line(&line);
+        }
+
+        self.in_comment = false;
+    }
+
+    /// Write a full line at the current depth
+    fn push_line(&mut self, line: &str) {
+        self.out.push_str(&INDENT.repeat(self.depth));
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minify() {
+        assert_eq!("+[->+<].", minify("+ [ -\n>+< ] print it ."));
+    }
+
+    #[test]
+    fn test_pretty() {
+        let src = "++[>++[-]<-]>.";
+        let expected = "++\n[\n    >++\n    [\n        -\n    ]\n    <-\n]\n>.\n";
+
+        assert_eq!(expected, pretty(src, false));
+    }
+
+    #[test]
+    fn test_pretty_comments() {
+        let src = "set up ++ twice\n[ loop >+<- ]\nprint .";
+        let expected = "set up ++ twice\n[\n    loop >+<-\n]\nprint .\n";
+
+        assert_eq!(expected, pretty(src, true));
+        assert_eq!("++\n[\n    >+<-\n]\n.\n", pretty(src, false));
+    }
+
+    #[test]
+    fn test_formatting_preserves_program() {
+        let src = include_str!("../samples/mandelbrot.bf");
+
+        assert_eq!(minify(src), minify(&pretty(src, true)));
+        assert_eq!(minify(src), minify(&pretty(src, false)));
+    }
+
+    #[test]
+    fn test_pretty_idempotent() {
+        let src = include_str!("../samples/primes.bf");
+        let once = pretty(src, true);
+
+        assert_eq!(once, pretty(&once, true));
+    }
+}
@@ -0,0 +1,249 @@
+//! The brainfuck VM `History`. Keeps an undo log of the cells
+//! each instruction wrote and where the pointers were, as an
+//! `Observer` of the VM, so execution can be rewound and replayed.
+
+use std::{collections::VecDeque, ops::ControlFlow};
+
+use crate::{
+    io::InputOutput,
+    observer::Observer,
+    vm::{VMError, VM},
+};
+
+/// A memory cell written by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellWrite {
+    pub cell: usize,
+    pub before: u8,
+    pub after: u8,
+}
+
+/// Where a VM was, and how much input and output it had done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    ip: usize,
+    ptr: usize,
+    steps: u64,
+    input_pos: u64,
+    output_pos: u64,
+}
+
+impl Position {
+    fn of<IO: InputOutput>(vm: &VM<IO>) -> Self {
+        Position {
+            ip: vm.ip(),
+            ptr: vm.ptr(),
+            steps: vm.steps(),
+            input_pos: vm.input_pos(),
+            output_pos: vm.output_pos(),
+        }
+    }
+
+    fn apply<IO: InputOutput>(&self, vm: &mut VM<IO>) {
+        vm.rewind(self.ip, self.ptr, self.steps, self.input_pos, self.output_pos);
+    }
+}
+
+/// Everything one instruction changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Change {
+    before: Position,
+    after: Position,
+    writes: Vec<CellWrite>,
+}
+
+/// Undo log for a `VM`, holding up to a fixed number of instructions.
+///
+/// Stepping back never un-does input or output, instead stepping
+/// forward again replays what the instructions did the first time,
+/// without reading or writing anything, until the VM is back where
+/// it was before stepping back.
+#[derive(Debug, Clone)]
+pub struct History {
+    /// Instructions that can be stepped back through, latest last
+    done: VecDeque<Change>,
+    /// Instructions that were stepped back through, latest first
+    undone: Vec<Change>,
+    /// Instructions kept in `done`, older ones are forgotten
+    limit: usize,
+    /// The instruction running, while observing a VM
+    running: Option<Change>,
+}
+
+impl History {
+    /// Create an empty history, remembering the last `limit` instructions,
+    /// or just the last one if `limit` is 0
+    pub fn new(limit: usize) -> Self {
+        History {
+            done: VecDeque::new(),
+            undone: Vec::new(),
+            limit,
+            running: None,
+        }
+    }
+
+    /// Run one instruction, or replay it if it was stepped back through.
+    /// Returns the cells the instruction wrote.
+    pub fn step<IO: InputOutput>(&mut self, vm: &mut VM<IO>) -> Result<&[CellWrite], VMError> {
+        match self.undone.pop() {
+            Some(change) => {
+                for write in &change.writes {
+                    vm.set_cell(write.cell, write.after);
+                }
+                change.after.apply(vm);
+                self.push(change);
+            }
+            None => {
+                let steps = vm.steps();
+                vm.run_observed(1, &mut *self)?;
+                if vm.steps() == steps {
+                    return Ok(&[]);
+                }
+            }
+        }
+
+        Ok(self.last_writes())
+    }
+
+    /// Undo the last instruction, returning the cells it wrote,
+    /// or `None` if there is no history left to undo.
+    pub fn back<IO: InputOutput>(&mut self, vm: &mut VM<IO>) -> Option<&[CellWrite]> {
+        let change = self.done.pop_back()?;
+        for write in &change.writes {
+            vm.set_cell(write.cell, write.before);
+        }
+        change.before.apply(vm);
+
+        self.undone.push(change);
+        self.undone.last().map(|change| &change.writes[..])
+    }
+
+    /// Whether there are instructions that were stepped back through, which
+    /// `step` replays. Running the VM while observed forgets them instead.
+    pub fn can_replay(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    /// Cells written by the last instruction run or replayed
+    pub fn last_writes(&self) -> &[CellWrite] {
+        self.done.back().map_or(&[], |change| &change.writes)
+    }
+
+    /// Index of the instruction `back` would undo
+    pub fn last_ip(&self) -> Option<usize> {
+        self.done.back().map(|change| change.before.ip)
+    }
+
+    /// Number of instructions that can be stepped back through
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Whether there is nothing to step back through
+    pub fn is_empty(&self) -> bool {
+        self.done.is_empty()
+    }
+
+    /// Remember a change, forgetting the oldest once over the limit.
+    /// The last change is always kept, since `step` returns its writes.
+    fn push(&mut self, change: Change) {
+        while self.done.len() >= self.limit.max(1) {
+            self.done.pop_front();
+        }
+        self.done.push_back(change);
+    }
+}
+
+/// Records what each instruction the VM runs changes
+impl Observer for History {
+    fn before<IO: InputOutput>(&mut self, vm: &VM<IO>, _ip: usize) -> ControlFlow<()> {
+        // the VM moved on from anything that was stepped back through
+        self.undone.clear();
+        let position = Position::of(vm);
+        self.running = Some(Change {
+            before: position,
+            after: position,
+            writes: Vec::new(),
+        });
+        ControlFlow::Continue(())
+    }
+
+    fn write(&mut self, cell: usize, before: u8, after: u8) {
+        if let Some(change) = &mut self.running {
+            change.writes.push(CellWrite { cell, before, after });
+        }
+    }
+
+    fn after<IO: InputOutput>(&mut self, vm: &VM<IO>, _ip: usize) -> ControlFlow<()> {
+        if let Some(mut change) = self.running.take() {
+            change.after = Position::of(vm);
+            self.push(change);
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::compile, io::MemoryIO};
+
+    use super::*;
+
+    #[test]
+    fn test_back_and_replay() {
+        let mut vm = VM::new_with_io(compile(",[->++<]>."), MemoryIO::new("\x03"));
+        let mut history = History::new(100);
+
+        let mut states = vec![vm.snapshot()];
+        while !vm.is_finished() {
+            history.step(&mut vm).unwrap();
+            states.push(vm.snapshot());
+        }
+        assert_eq!(b"\x06", vm.io().output());
+
+        // every state can be rewound to, in reverse
+        for state in states.iter().rev().skip(1) {
+            history.back(&mut vm).unwrap();
+            assert_eq!(*state, vm.snapshot());
+        }
+        assert_eq!(None, history.back(&mut vm));
+
+        // replaying doesn't read input or write output again
+        while !vm.is_finished() {
+            history.step(&mut vm).unwrap();
+        }
+        assert_eq!(*states.last().unwrap(), vm.snapshot());
+        assert_eq!(b"\x06", vm.io().output());
+    }
+
+    #[test]
+    fn test_writes_and_limit() {
+        let mut vm = VM::new_with_io(compile("++>+<[->>+<<]"), MemoryIO::new(""));
+        let mut history = History::new(2);
+
+        assert_eq!(
+            [CellWrite {
+                cell: 0,
+                before: 0,
+                after: 2
+            }],
+            history.step(&mut vm).unwrap()
+        );
+        history.step(&mut vm).unwrap();
+        history.step(&mut vm).unwrap();
+        assert_eq!(2, history.len());
+
+        // only the last 2 instructions can be undone
+        history.back(&mut vm).unwrap();
+        history.back(&mut vm).unwrap();
+        assert_eq!(1, vm.ip());
+        assert!(history.back(&mut vm).is_none());
+
+        // a limit of 0 still keeps the last instruction
+        let mut history = History::new(0);
+        for _ in 0..3 {
+            history.step(&mut vm).unwrap();
+        }
+        assert_eq!(1, history.len());
+    }
+}
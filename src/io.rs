@@ -1,76 +1,421 @@
 //! The io module. Defines the `InputOutput` trait
 //! and several implementors
 
-use core::panic;
-use std::{cell::RefCell, collections::VecDeque, io::Read, rc::Rc};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    io::{self, BufWriter, ErrorKind, Read, StdoutLock, Write},
+    rc::Rc,
+};
 
 /// Generic IO trait
 pub trait InputOutput {
+    /// Get a single byte of input, or `None` at the end of input
+    fn getch(&mut self) -> io::Result<Option<u8>>;
+    /// Output a single byte
+    fn print(&mut self, byte: u8) -> io::Result<()>;
+    /// Output several bytes at once
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        bytes.iter().try_for_each(|byte| self.print(*byte))
+    }
+    /// Flush any buffered output.
+    /// Called by the VM whenever it stops running, whether the program
+    /// ended or not, so it may be called many times in one run.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Infallible IO trait taking `&self`, the original shape of `InputOutput`.
+/// Every `SimpleIO` is an `InputOutput` through a blanket impl,
+/// so existing implementors keep working unchanged.
+pub trait SimpleIO {
     /// Get a single byte of input
     fn getch(&self) -> u8;
     /// Output a single byte
     fn print(&self, byte: u8);
+    /// Flush any buffered output
+    fn flush(&self) {}
+}
+
+impl<T: SimpleIO> InputOutput for T {
+    #[inline]
+    fn getch(&mut self) -> io::Result<Option<u8>> {
+        Ok(Some(SimpleIO::getch(self)))
+    }
+
+    #[inline]
+    fn print(&mut self, byte: u8) -> io::Result<()> {
+        SimpleIO::print(self, byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        SimpleIO::flush(self);
+        Ok(())
+    }
+}
+
+/// Helper to read a single byte, returning `None` at the end of input
+fn read_byte(reader: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    match reader.read_exact(&mut byte) {
+        Ok(()) => Ok(Some(byte[0])),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// InputOutput implementor for stdin/stdout.
+/// Output bytes are written as-is through a buffer,
+/// which is flushed before reading input.
+pub struct StdIO {
+    out: BufWriter<StdoutLock<'static>>,
 }
 
-/// InputOutput implementor for stdin/stdout
-pub struct StdIO {}
+impl StdIO {
+    /// Create a new `StdIO` holding a lock on stdout until it is dropped
+    pub fn new() -> Self {
+        StdIO {
+            out: BufWriter::new(io::stdout().lock()),
+        }
+    }
+}
+
+impl Default for StdIO {
+    fn default() -> Self {
+        StdIO::new()
+    }
+}
 
 impl InputOutput for StdIO {
     #[inline]
-    fn getch(&self) -> u8 {
-        std::io::stdin().bytes().next().unwrap().unwrap()
+    fn getch(&mut self) -> io::Result<Option<u8>> {
+        // make sure any prompt is visible before blocking on input
+        self.out.flush()?;
+
+        // stdin is only locked while reading, so others can still use it
+        read_byte(&mut io::stdin().lock())
     }
 
     #[inline]
-    fn print(&self, byte: u8) {
-        print!("{}", byte as char)
+    fn print(&mut self, byte: u8) -> io::Result<()> {
+        self.out.write_all(&[byte])
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
     }
 }
 
-/// Test InputOutput implementor
-pub struct TestIO {
-    input: RefCell<VecDeque<u8>>,
-    output: RefCell<String>,
+/// InputOutput adapter for any `Read` and `Write` pair,
+/// such as files, sockets, pipes, `Cursor`s or `Vec<u8>`.
+/// To keep the output after the VM has run, pass `&mut` to the writer.
+///
+/// Reads and writes go straight to `R` and `W`, wrap them
+/// in a `BufReader` or `BufWriter` if they are unbuffered.
+pub struct ReaderWriterIO<R: Read, W: Write> {
+    reader: R,
+    writer: W,
 }
 
-#[cfg(test)]
-impl TestIO {
-    pub fn new(input: &str) -> Self {
-        TestIO {
-            // note: bytes instead of char, trusting test input here
-            input: RefCell::new(input.to_string().bytes().collect()),
-            output: RefCell::new(String::new()),
+impl<R: Read, W: Write> ReaderWriterIO<R, W> {
+    /// Create a new `ReaderWriterIO` reading from `reader` and writing to `writer`
+    pub fn new(reader: R, writer: W) -> Self {
+        ReaderWriterIO { reader, writer }
+    }
+
+    /// Unwrap this adapter, returning the reader and writer
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R: Read, W: Write> InputOutput for ReaderWriterIO<R, W> {
+    fn getch(&mut self) -> io::Result<Option<u8>> {
+        read_byte(&mut self.reader)
+    }
+
+    fn print(&mut self, byte: u8) -> io::Result<()> {
+        self.writer.write_all(&[byte])
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// In-memory InputOutput implementor.
+/// Input is read from a byte buffer and output is collected into another,
+/// get it back after running with `VM::io` or `VM::into_io`.
+/// Reading past the end of the input buffer is the end of input.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryIO {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl MemoryIO {
+    /// Create a new `MemoryIO` reading from the given input
+    pub fn new(input: impl AsRef<[u8]>) -> Self {
+        MemoryIO {
+            input: input.as_ref().iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Append bytes to the end of the input
+    pub fn push_input(&mut self, bytes: impl AsRef<[u8]>) {
+        self.input.extend(bytes.as_ref());
+    }
+
+    /// Input not yet read
+    pub fn remaining_input(&self) -> usize {
+        self.input.len()
+    }
+
+    /// Output written so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Take the output written so far, leaving the buffer empty
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Unwrap this `MemoryIO`, returning the output
+    pub fn into_output(self) -> Vec<u8> {
+        self.output
+    }
+}
+
+impl InputOutput for MemoryIO {
+    #[inline]
+    fn getch(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+
+    #[inline]
+    fn print(&mut self, byte: u8) -> io::Result<()> {
+        self.output.push(byte);
+        Ok(())
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Byte that `NewlineIO` presents for a line break in the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Newline {
+    /// Line feed, `\n` (10)
+    Lf,
+    /// Carriage return, `\r` (13)
+    Cr,
+}
+
+impl Newline {
+    /// The byte for this newline
+    pub fn byte(self) -> u8 {
+        match self {
+            Newline::Lf => b'\n',
+            Newline::Cr => b'\r',
+        }
+    }
+}
+
+/// InputOutput wrapper translating line breaks in the input.
+/// Each `\r\n`, `\r` or `\n` read from the inner `IO` is presented
+/// to the program as a single `Newline` byte. Output is passed through.
+pub struct NewlineIO<IO: InputOutput> {
+    inner: IO,
+    newline: Newline,
+    /// Whether the last byte read was `\r`, so a following `\n` is part of a `\r\n`
+    after_cr: bool,
+}
+
+impl<IO: InputOutput> NewlineIO<IO> {
+    /// Wrap `inner`, presenting each line break it reads as `newline`
+    pub fn new(inner: IO, newline: Newline) -> Self {
+        NewlineIO {
+            inner,
+            newline,
+            after_cr: false,
         }
     }
 
-    pub fn output(&self) -> String {
-        self.output.borrow().clone()
+    /// Unwrap this wrapper, returning the inner `IO`
+    pub fn into_inner(self) -> IO {
+        self.inner
     }
 }
 
-impl InputOutput for Rc<TestIO> {
-    fn getch(&self) -> u8 {
-        self.input.borrow_mut().pop_front().unwrap()
+impl<IO: InputOutput> InputOutput for NewlineIO<IO> {
+    fn getch(&mut self) -> io::Result<Option<u8>> {
+        // never read ahead after a `\r`, that would block interactive input
+        let mut byte = self.inner.getch()?;
+        if self.after_cr && byte == Some(b'\n') {
+            byte = self.inner.getch()?;
+        }
+
+        self.after_cr = byte == Some(b'\r');
+        Ok(match byte {
+            Some(b'\r' | b'\n') => Some(self.newline.byte()),
+            other => other,
+        })
+    }
+
+    fn print(&mut self, byte: u8) -> io::Result<()> {
+        self.inner.print(byte)
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Step count shared between a VM and its `IO`. A VM given a
+/// clone with `share_steps` sets it before every read or write,
+/// so wrappers like `replay::Recorder` know the step it happened at.
+#[derive(Debug, Clone, Default)]
+pub struct StepCounter(Rc<Cell<u64>>);
+
+impl StepCounter {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn print(&self, byte: u8) {
-        self.output.borrow_mut().push(byte as char);
+    /// Steps run before the current read or write
+    pub fn get(&self) -> u64 {
+        self.0.get()
+    }
+
+    pub fn set(&self, steps: u64) {
+        self.0.set(steps);
     }
 }
 
 /// 0-cost InputOutput implementor.
 /// Intended for benchmarking.
-/// `getch()` will panic, and `print()` will be ignored.
+/// `getch()` will return an error, and `print()` will be ignored.
 #[derive(Clone)]
 pub struct NoIO {}
 impl InputOutput for NoIO {
     #[inline]
-    fn getch(&self) -> u8 {
-        panic!("No Input supported!")
+    fn getch(&mut self) -> io::Result<Option<u8>> {
+        Err(io::Error::new(ErrorKind::Unsupported, "No Input supported!"))
     }
 
     #[inline]
-    fn print(&self, _: u8) {
-        // we don't have to panic here, just do nothing
+    fn print(&mut self, _: u8) -> io::Result<()> {
+        // we don't have to error here, just do nothing
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::{
+        compiler::compile,
+        vm::{VMError, VM},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_reader_writer_io() {
+        // echo input until the end of input
+        let p = compile(",[.,]");
+        let mut output = Vec::new();
+        let io = ReaderWriterIO::new(&b"echo \xff"[..], &mut output);
+
+        VM::new_with_io(p, io).run().unwrap();
+        assert_eq!(b"echo \xff", &output[..]);
+    }
+
+    #[test]
+    fn test_reader_writer_io_eof() {
+        // reads past the end of input store 0, so "A" is printed as "A\0"
+        let p = compile(",.,.");
+        let mut output = Vec::new();
+        let io = ReaderWriterIO::new(std::io::Cursor::new("A"), &mut output);
+
+        VM::new_with_io(p, io).run().unwrap();
+        assert_eq!(b"A\0", &output[..]);
+    }
+
+    #[test]
+    fn test_memory_io() {
+        let p = compile(",[.,]+.");
+        let mut vm = VM::new_with_io(p, MemoryIO::new("echo"));
+        vm.io_mut().push_input(b"\xff");
+
+        vm.run().unwrap();
+        assert_eq!(b"echo\xff\x01", vm.io().output());
+        assert_eq!(0, vm.io().remaining_input());
+        assert_eq!(b"echo\xff\x01".to_vec(), vm.into_io().into_output());
+    }
+
+    #[test]
+    fn test_newline_io() {
+        let input = MemoryIO::new("a\r\nb\rc\nd\r\r\n");
+        let mut io = NewlineIO::new(input, Newline::Lf);
+
+        let mut read = Vec::new();
+        while let Some(byte) = io.getch().unwrap() {
+            read.push(byte);
+        }
+        assert_eq!(b"a\nb\nc\nd\n\n", &read[..]);
+
+        let io = NewlineIO::new(MemoryIO::new("x\r\n\n"), Newline::Cr);
+        let mut vm = VM::new_with_io(compile(",.,.,.,."), io);
+        vm.run().unwrap();
+        assert_eq!(b"x\r\r\0", &vm.into_io().into_inner().into_output()[..]);
+    }
+
+    #[test]
+    fn test_io_errors_stop_vm() {
+        let p = compile("+.,.");
+
+        let err = VM::new_with_io(p, NoIO {}).run().unwrap_err();
+        assert!(matches!(err, VMError::Io(err) if err.kind() == ErrorKind::Unsupported));
+    }
+
+    #[test]
+    fn test_simple_io_adapter() {
+        /// Legacy-style implementor, adapted by the blanket impl
+        struct Upper(RefCell<Vec<u8>>);
+        impl SimpleIO for Upper {
+            fn getch(&self) -> u8 {
+                b'a'
+            }
+
+            fn print(&self, byte: u8) {
+                self.0.borrow_mut().push(byte.to_ascii_uppercase());
+            }
+        }
+
+        let mut io = Upper(RefCell::new(Vec::new()));
+        assert_eq!(Some(b'a'), InputOutput::getch(&mut io).unwrap());
+
+        InputOutput::write_all(&mut io, b"abc").unwrap();
+        assert_eq!(b"ABC", &io.0.borrow()[..]);
     }
 }
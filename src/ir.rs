@@ -0,0 +1,130 @@
+//! The compiler's intermediate representation.
+//! A tree of `Node`s where each loop owns its body,
+//! so optimizations can match loops structurally.
+//! Lowered to flat `Instruction`s once optimization is done.
+
+use crate::{
+    compiler::{CompileError, Span, Spanned},
+    instruction::Instruction,
+};
+
+/// Node of the intermediate representation tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// Any instruction other than `Loop` or `End`
+    Ins(Instruction, Span),
+    /// A loop, spanning from its `[` to its `]`
+    Loop { body: Vec<Node>, span: Span },
+}
+
+/// Build the IR tree from parsed instructions.
+/// Fails on the first unmatched `]`, or the innermost unmatched `[`.
+pub fn build(instructions: Vec<Spanned>) -> Result<Vec<Node>, CompileError> {
+    // stack of enclosing loops, with the span of their `[`
+    let mut stack: Vec<(Vec<Node>, Span)> = Vec::new();
+    let mut current = Vec::new();
+
+    for (ins, span) in instructions {
+        match ins {
+            Instruction::Loop => {
+                stack.push((current, span));
+                current = Vec::new();
+            }
+            Instruction::End => {
+                let (parent, open) = stack.pop().ok_or(CompileError::UnmatchedEnd(span))?;
+                let body = std::mem::replace(&mut current, parent);
+                current.push(Node::Loop {
+                    body,
+                    span: open.join(span),
+                });
+            }
+            other => current.push(Node::Ins(other, span)),
+        }
+    }
+
+    match stack.pop() {
+        Some((_, open)) => Err(CompileError::UnmatchedLoop(open)),
+        None => Ok(current),
+    }
+}
+
+/// Lower the IR tree back into flat instructions
+pub fn lower(nodes: Vec<Node>) -> Vec<Spanned> {
+    let mut output = Vec::new();
+    lower_into(nodes, &mut output);
+    output
+}
+
+fn lower_into(nodes: Vec<Node>, output: &mut Vec<Spanned>) {
+    for node in nodes {
+        match node {
+            Node::Ins(ins, span) => output.push((ins, span)),
+            Node::Loop { body, span } => {
+                // the brackets are the first and last byte of the loop's span
+                output.push((
+                    Instruction::Loop,
+                    Span {
+                        start: span.start,
+                        end: span.start + 1,
+                    },
+                ));
+                lower_into(body, output);
+                output.push((
+                    Instruction::End,
+                    Span {
+                        start: span.end - 1,
+                        end: span.end,
+                    },
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::parse;
+
+    #[test]
+    fn test_build_nested() {
+        use Instruction::*;
+        let nodes = build(parse("+[>[-]]")).unwrap();
+
+        assert_eq!(
+            vec![
+                Node::Ins(Alt(1), Span { start: 0, end: 1 }),
+                Node::Loop {
+                    body: vec![
+                        Node::Ins(Shift(1), Span { start: 2, end: 3 }),
+                        Node::Loop {
+                            body: vec![Node::Ins(Alt(-1), Span { start: 4, end: 5 })],
+                            span: Span { start: 3, end: 6 },
+                        },
+                    ],
+                    span: Span { start: 1, end: 7 },
+                },
+            ],
+            nodes
+        );
+    }
+
+    #[test]
+    fn test_lower_round_trip() {
+        let parsed = parse("+[>[-]<.]x,");
+
+        assert_eq!(parsed, lower(build(parsed.clone()).unwrap()));
+    }
+
+    #[test]
+    fn test_build_unbalanced() {
+        assert_eq!(
+            Err(CompileError::UnmatchedLoop(Span { start: 0, end: 1 })),
+            build(parse("[[]"))
+        );
+        assert_eq!(
+            Err(CompileError::UnmatchedEnd(Span { start: 2, end: 3 })),
+            build(parse("[]]["))
+        );
+    }
+}
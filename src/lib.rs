@@ -3,7 +3,26 @@
 //!
 //! brainfrick-rs
 
+pub mod asm;
+pub mod bytecode;
 pub mod compiler;
+pub mod coverage;
+pub mod debugger;
+pub mod decoded;
+pub mod dump;
+pub mod formatter;
+pub mod history;
 pub mod instruction;
 pub mod io;
+pub mod ir;
+pub mod observer;
+pub mod profiler;
+pub mod repl;
+pub mod replay;
+pub mod resumable;
+pub mod snapshot;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub mod visualizer;
 pub mod vm;
+pub mod watch;
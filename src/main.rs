@@ -3,31 +3,1084 @@
 //!
 //! brainfrick-rs
 
-use std::{fs, path::PathBuf};
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
-use argh::FromArgs;
-use brainfrick_rs::{compiler::compile, vm::VM};
+use argh::{FromArgs, SubCommands};
+#[cfg(feature = "trace")]
+use brainfrick_rs::trace::{self, Filter, Tracer};
+use brainfrick_rs::{
+    asm,
+    compiler::{self, try_compile, CompileError, Program},
+    coverage::Coverage,
+    debugger::Debugger,
+    dump, formatter,
+    io::{InputOutput, Newline, NewlineIO, ReaderWriterIO, StdIO},
+    profiler::Profile,
+    repl::Repl,
+    replay::{Recorder, ReplayIO},
+    snapshot::Snapshot,
+    visualizer::Visualizer,
+    vm::{VMError, VM},
+    watch::{Breakpoint, Watchpoint},
+};
 
 #[derive(FromArgs)]
 /// Brainfuck interpreter arguments.
-/// Usage: `bfrs <filepath>`
+/// Usage: `bfrs <filepath>` (shorthand for `bfrs run <filepath>`)
 struct Args {
-    #[argh(positional, description = "brainfuck source file")]
-    file: PathBuf,
+    #[argh(subcommand)]
+    command: Command,
+}
+
+// parsed once, so the size of `RunArgs` doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Run(RunArgs),
+    Compile(CompileArgs),
+    Emit(EmitArgs),
+    Fmt(FmtArgs),
+    Check(CheckArgs),
+    Debug(DebugArgs),
+    Repl(ReplArgs),
+    Replay(ReplayArgs),
+    Visualize(VisualizeArgs),
+}
+
+#[derive(FromArgs)]
+/// Compile and run a brainfuck program.
+/// Usage: `bfrs run <filepath>` or `bfrs run -e <code>`
+#[argh(subcommand, name = "run")]
+struct RunArgs {
+    #[argh(positional, description = "brainfuck source or bytecode file")]
+    file: Option<PathBuf>,
+
+    #[argh(
+        option,
+        short = 'e',
+        description = "brainfuck source to run instead of a file"
+    )]
+    eval: Option<String>,
+
+    #[argh(option, description = "file to read program input from, instead of stdin")]
+    input: Option<PathBuf>,
+
+    #[argh(
+        option,
+        from_str_fn(unescape),
+        description = "program input, supports \\n \\r \\t \\0 \\\\ and \\xHH escapes"
+    )]
+    input_str: Option<Vec<u8>>,
+
+    #[argh(
+        option,
+        short = 'o',
+        description = "file to write program output to, instead of stdout"
+    )]
+    output: Option<PathBuf>,
+
+    #[argh(
+        option,
+        from_str_fn(parse_newline),
+        description = "present input line breaks (CRLF, CR or LF) as lf (10) or cr (13)"
+    )]
+    newline: Option<Newline>,
+
+    #[argh(option, description = "record the session to a log for `bfrs replay`")]
+    record: Option<PathBuf>,
+
+    #[argh(option, description = "stop with an error after this many instructions")]
+    max_steps: Option<u64>,
+
+    #[argh(
+        option,
+        description = "save a snapshot to resume from on Ctrl-C or at the step limit"
+    )]
+    snapshot: Option<PathBuf>,
+
+    #[argh(
+        option,
+        description = "resume from a snapshot instead of running a file, skipping the input it already read"
+    )]
+    resume: Option<PathBuf>,
+
+    #[argh(
+        switch,
+        description = "exit with the value of the current cell when the program ends"
+    )]
+    exit_cell: bool,
+
+    #[argh(
+        switch,
+        description = "count instructions run, and report the hottest loops on stderr"
+    )]
+    profile: bool,
+
+    #[argh(
+        option,
+        description = "write the source annotated with how often each line ran to a file"
+    )]
+    coverage: Option<PathBuf>,
+
+    #[argh(switch, description = "write `--coverage` as an lcov tracefile instead")]
+    lcov: bool,
+
+    #[argh(
+        option,
+        description = "log each instruction run to a file, needs the `trace` feature"
+    )]
+    trace: Option<PathBuf>,
+
+    #[argh(
+        option,
+        default = "String::from(\"json\")",
+        description = "trace format, json (default) or binary"
+    )]
+    trace_format: String,
+
+    #[argh(option, description = "only trace these steps, like `100..200`")]
+    trace_steps: Option<String>,
+
+    #[argh(
+        option,
+        description = "only trace these instruction kinds, like `in,out,loop`"
+    )]
+    trace_ins: Option<String>,
+
+    #[argh(
+        option,
+        from_str_fn(parse_tape_dump),
+        description = "dump memory when the program stops: hex, window[:<rows>] (around the pointer) or raw:<file>"
+    )]
+    dump_tape: Option<TapeDump>,
 
     #[argh(switch, short = 's', description = "show compiled instructions")]
     show: bool,
 }
 
+#[derive(FromArgs)]
+/// Compile a brainfuck program to bytecode.
+/// Usage: `bfrs compile <filepath> -o <out.bfc>`
+#[argh(subcommand, name = "compile")]
+struct CompileArgs {
+    #[argh(positional, description = "brainfuck source file")]
+    file: PathBuf,
+
+    #[argh(option, short = 'o', description = "output file, defaults to <file>.bfc")]
+    output: Option<PathBuf>,
+
+    #[argh(switch, description = "omit the source map from the output")]
+    strip: bool,
+}
+
+#[derive(FromArgs)]
+/// Compile a brainfuck program for another backend.
+/// Usage: `bfrs emit asm <filepath> -o <out.s>`
+#[argh(subcommand, name = "emit")]
+struct EmitArgs {
+    #[argh(positional, description = "backend to emit (asm)")]
+    backend: Backend,
+
+    #[argh(positional, description = "brainfuck source or bytecode file")]
+    file: PathBuf,
+
+    #[argh(option, short = 'o', description = "output file, defaults to stdout")]
+    output: Option<PathBuf>,
+}
+
+#[derive(FromArgs)]
+/// Format brainfuck source code.
+/// Usage: `bfrs fmt <filepath> [--minify] [--comments]`
+#[argh(subcommand, name = "fmt")]
+struct FmtArgs {
+    #[argh(positional, description = "brainfuck source file")]
+    file: PathBuf,
+
+    #[argh(switch, short = 'm', description = "minify instead of pretty-printing")]
+    minify: bool,
+
+    #[argh(switch, short = 'c', description = "keep comments when pretty-printing")]
+    comments: bool,
+
+    #[argh(
+        switch,
+        short = 'i',
+        description = "rewrite the file in place instead of printing it"
+    )]
+    in_place: bool,
+}
+
+#[derive(FromArgs)]
+/// Check brainfuck source for errors without running it.
+/// Usage: `bfrs check <filepath>...`
+#[argh(subcommand, name = "check")]
+struct CheckArgs {
+    #[argh(positional, description = "brainfuck source files")]
+    files: Vec<PathBuf>,
+}
+
+#[derive(FromArgs)]
+/// Step through a brainfuck program in an interactive debugger.
+/// Debugger commands are read from stdin, so program input
+/// comes from `--input` or `--input-str`, and is empty otherwise.
+/// Usage: `bfrs debug <filepath> [-b <breakpoint>...] [-w <watchpoint>...]`
+#[argh(subcommand, name = "debug")]
+struct DebugArgs {
+    #[argh(positional, description = "brainfuck source or bytecode file")]
+    file: PathBuf,
+
+    #[argh(option, description = "file to read program input from")]
+    input: Option<PathBuf>,
+
+    #[argh(
+        option,
+        from_str_fn(unescape),
+        description = "program input, supports \\n \\r \\t \\0 \\\\ and \\xHH escapes"
+    )]
+    input_str: Option<Vec<u8>>,
+
+    #[argh(
+        option,
+        short = 'b',
+        description = "set a breakpoint on an instruction index, like `12` or `12 if mem[3] == 0`"
+    )]
+    breakpoint: Vec<Breakpoint>,
+
+    #[argh(
+        option,
+        short = 'w',
+        description = "set a watchpoint, like `3` (writes to cell 3), `3 == 10` or `ptr 5..8`"
+    )]
+    watch: Vec<Watchpoint>,
+}
+
+#[derive(FromArgs)]
+/// Run brainfuck interactively, one line at a time, on the same memory.
+/// Usage: `bfrs repl [-w <cells>]`
+#[argh(subcommand, name = "repl")]
+struct ReplArgs {
+    #[argh(
+        option,
+        short = 'w',
+        default = "8",
+        description = "memory cells to show either side of the pointer"
+    )]
+    window: usize,
+}
+
+#[derive(FromArgs)]
+/// Replay a session recorded with `bfrs run --record`,
+/// checking the program's output matches the recording.
+/// Usage: `bfrs replay <filepath> <log>`
+#[argh(subcommand, name = "replay")]
+struct ReplayArgs {
+    #[argh(positional, description = "brainfuck source or bytecode file")]
+    file: PathBuf,
+
+    #[argh(positional, description = "session log written by `bfrs run --record`")]
+    log: PathBuf,
+}
+
+#[derive(FromArgs)]
+/// Watch a brainfuck program run in a full-screen terminal view.
+/// Usage: `bfrs visualize <filepath> [--speed <n>]`
+#[argh(subcommand, name = "visualize")]
+struct VisualizeArgs {
+    #[argh(positional, description = "brainfuck source file")]
+    file: PathBuf,
+
+    #[argh(
+        option,
+        description = "file to read program input from, instead of typing it in"
+    )]
+    input: Option<PathBuf>,
+
+    #[argh(
+        option,
+        from_str_fn(unescape),
+        description = "program input, supports \\n \\r \\t \\0 \\\\ and \\xHH escapes"
+    )]
+    input_str: Option<Vec<u8>>,
+
+    #[argh(
+        option,
+        default = "10",
+        description = "instructions run per second to start with"
+    )]
+    speed: u64,
+}
+
+/// Loops and instructions shown by `bfrs run --profile`
+const PROFILE_TOP: usize = 10;
+
+/// Rows either side of the pointer shown by `bfrs run --dump-tape window`
+const WINDOW_ROWS: usize = 2;
+
+/// Steps run between checks for Ctrl-C, when `bfrs run --snapshot` is set
+const INTERRUPT_CHUNK: u64 = 1 << 20;
+
+/// Exit status for each kind of error `bfrs` reports
+#[derive(Debug, Clone, Copy)]
+enum Exit {
+    /// Invalid command line arguments
+    Usage = 2,
+    /// The program failed to compile, or its bytecode failed to load
+    Compile = 3,
+    /// The program failed while running, or a replay diverged
+    Runtime = 4,
+    /// The program ran for more than `--max-steps` instructions
+    StepLimit = 5,
+    /// Reading or writing a file, or the program's input or output, failed
+    Io = 6,
+    /// The program was stopped by Ctrl-C, after saving a `--snapshot`
+    Interrupted = 130,
+}
+
+/// Options for how `bfrs run` executes a program
+struct RunOptions {
+    /// Name of the program, for error messages
+    name: String,
+    /// Source of the program, if known, for error messages
+    src: Option<String>,
+    newline: Option<Newline>,
+    record: Option<PathBuf>,
+    max_steps: Option<u64>,
+    snapshot: Option<PathBuf>,
+    resume: Option<Snapshot>,
+    exit_cell: bool,
+    profile: bool,
+    coverage: Option<PathBuf>,
+    lcov: bool,
+    trace: Option<TraceOptions>,
+    dump_tape: Option<TapeDump>,
+}
+
+/// Memory dump written by `bfrs run --dump-tape`
+enum TapeDump {
+    /// The whole tape as a `dump::hex` on stderr
+    Hex,
+    /// The rows around the pointer as a `dump::window` on stderr
+    Window(usize),
+    /// The tape's raw bytes, to a file
+    Raw(PathBuf),
+}
+
+/// Options for `bfrs run --trace`
+#[cfg_attr(not(feature = "trace"), allow(dead_code))]
+struct TraceOptions {
+    path: PathBuf,
+    format: String,
+    steps: Option<String>,
+    kinds: Option<String>,
+}
+
+/// Backends supported by `bfrs emit`
+enum Backend {
+    /// x86-64 Linux assembly for GNU `as`
+    Asm,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asm" => Ok(Backend::Asm),
+            other => Err(format!("unknown backend `{other}`, expected one of: asm")),
+        }
+    }
+}
+
 fn main() {
-    let args: Args = argh::from_env();
-    let src = fs::read_to_string(args.file).expect("could not open file");
-    let program = compile(&src);
+    match parse_args().command {
+        Command::Run(args) => run(args),
+        Command::Compile(args) => compile_bytecode(args),
+        Command::Emit(args) => emit(args),
+        Command::Fmt(args) => fmt(args),
+        Command::Check(args) => check(args),
+        Command::Debug(args) => debug(args),
+        Command::Repl(args) => repl(args),
+        Command::Replay(args) => replay(args),
+        Command::Visualize(args) => visualize(args),
+    }
+}
+
+/// Parse `Args` from the environment, treating any invocation that
+/// doesn't name a subcommand as `bfrs run ...`
+fn parse_args() -> Args {
+    let strings: Vec<String> = std::env::args().collect();
+    let cmd = strings[0].as_str();
+    let mut rest: Vec<&str> = strings[1..].iter().map(String::as_str).collect();
+
+    let is_command = |arg: &str| arg == "help" || Command::COMMANDS.iter().any(|c| c.name == arg);
+    if let Some(first) = rest.first() {
+        if !is_command(first) && *first != "--help" {
+            rest.insert(0, "run");
+        }
+    }
+
+    Args::from_args(&[cmd], &rest).unwrap_or_else(|early_exit| {
+        std::process::exit(match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                0
+            }
+            Err(()) => {
+                eprintln!("{}\nRun {} --help for more information.", early_exit.output, cmd);
+                Exit::Usage as i32
+            }
+        })
+    })
+}
+
+/// Print a usage error and exit, like argh does for invalid arguments
+fn usage_error(msg: &str) -> ! {
+    let cmd = std::env::args().next().unwrap_or_else(|| "bfrs".to_string());
+    eprintln!("{msg}\nRun {cmd} --help for more information.");
+    std::process::exit(Exit::Usage as i32)
+}
+
+/// Print an error message and exit with the status for its kind
+fn fail(exit: Exit, msg: impl Display) -> ! {
+    eprintln!("error: {msg}");
+    std::process::exit(exit as i32)
+}
+
+/// Unwrap a `Result`, or `fail` with its error and some context
+trait OrExit<T> {
+    fn or_exit(self, exit: Exit, context: impl Display) -> T;
+}
+
+impl<T, E: Display> OrExit<T> for Result<T, E> {
+    fn or_exit(self, exit: Exit, context: impl Display) -> T {
+        self.unwrap_or_else(|err| fail(exit, format!("{context}: {err}")))
+    }
+}
+
+/// Parse a string with backslash escapes into bytes.
+/// Supports `\n`, `\r`, `\t`, `\0`, `\\` and `\xHH`.
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        let byte = match chars.next() {
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('0') => b'\0',
+            Some('\\') => b'\\',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 2)
+                    .ok_or_else(|| format!("invalid escape `\\x{hex}`, expected two hex digits"))?
+            }
+            Some(other) => return Err(format!("unknown escape `\\{other}`")),
+            None => return Err("trailing `\\` in input".to_string()),
+        };
+        bytes.push(byte);
+    }
+
+    Ok(bytes)
+}
+
+/// Parse a `Newline` for `bfrs run --newline`
+fn parse_newline(s: &str) -> Result<Newline, String> {
+    match s {
+        "lf" => Ok(Newline::Lf),
+        "cr" => Ok(Newline::Cr),
+        other => Err(format!("unknown newline `{other}`, expected one of: lf, cr")),
+    }
+}
+
+fn parse_tape_dump(s: &str) -> Result<TapeDump, String> {
+    match s.split_once(':') {
+        None if s == "hex" => Ok(TapeDump::Hex),
+        None if s == "window" => Ok(TapeDump::Window(WINDOW_ROWS)),
+        Some(("window", rows)) => rows
+            .parse()
+            .map(TapeDump::Window)
+            .map_err(|_| format!("invalid window rows `{rows}`")),
+        Some(("raw", path)) if !path.is_empty() => Ok(TapeDump::Raw(PathBuf::from(path))),
+        _ => Err(format!(
+            "unknown tape dump `{s}`, expected one of: hex, window, window:<rows>, raw:<file>"
+        )),
+    }
+}
+
+/// Describe a `CompileError` in `src` by line and column, like `name:1:2: message`
+fn describe_error(name: &str, src: &str, err: CompileError) -> String {
+    let (line, col) = err.span().line_col(src);
+    format!("{name}:{line}:{col}: {err}")
+}
+
+/// Compile brainfuck source, exiting with an error message if it's invalid
+fn compile_source(name: &str, src: &str) -> Program {
+    try_compile(src).unwrap_or_else(|err| fail(Exit::Compile, describe_error(name, src, err)))
+}
+
+/// Load a `Program` from either brainfuck source or bytecode,
+/// along with the source if there is any
+fn load_source(path: &Path) -> (Program, Option<String>) {
+    let name = path.display();
+    let bytes = fs::read(path).or_exit(Exit::Io, format_args!("could not read {name}"));
+
+    if Program::is_bytecode(&bytes) {
+        let program = Program::from_bytes(&bytes)
+            .or_exit(Exit::Compile, format_args!("could not load bytecode from {name}"));
+        (program, None)
+    } else {
+        let src = String::from_utf8(bytes).or_exit(Exit::Compile, format_args!("{name} is not valid UTF-8"));
+        (compile_source(&name.to_string(), &src), Some(src))
+    }
+}
+
+/// Load a `Program` from either brainfuck source or bytecode
+fn load_program(path: &Path) -> Program {
+    load_source(path).0
+}
+
+/// Read a source file, exiting with an error message if it can't be read
+fn read_source(path: &Path) -> String {
+    fs::read_to_string(path).or_exit(Exit::Io, format_args!("could not read {}", path.display()))
+}
+
+/// Write a file, exiting with an error message if it can't be written
+fn write_file(path: &Path, contents: impl AsRef<[u8]>) {
+    fs::write(path, contents).or_exit(Exit::Io, format_args!("could not write {}", path.display()))
+}
+
+/// Program input from `--input` or `--input-str`, if either is set
+fn program_input(input: Option<PathBuf>, input_str: Option<Vec<u8>>) -> Option<Box<dyn Read>> {
+    match (input, input_str) {
+        (None, None) => None,
+        (Some(path), None) => {
+            let file = File::open(&path).or_exit(Exit::Io, format_args!("could not open {}", path.display()));
+            Some(Box::new(BufReader::new(file)))
+        }
+        (None, Some(bytes)) => Some(Box::new(Cursor::new(bytes))),
+        (Some(_), Some(_)) => usage_error("`--input` and `--input-str` can't be used together"),
+    }
+}
+
+fn run(args: RunArgs) {
+    let resume = args.resume.as_deref().map(load_snapshot);
+    let (program, src, name) = match (&args.file, args.eval, &resume) {
+        (Some(file), None, None) => {
+            let (program, src) = load_source(file);
+            (program, src, file.display().to_string())
+        }
+        (None, Some(code), None) => (compile_source("<eval>", &code), Some(code), "<eval>".to_string()),
+        (None, None, Some(snapshot)) => {
+            let name = args.resume.as_ref().map(|path| path.display().to_string());
+            (snapshot.program.clone(), None, name.unwrap_or_default())
+        }
+        _ => usage_error("expected either a file, `-e <code>` or `--resume <snapshot>`"),
+    };
 
     if args.show {
         println!("{program}");
     }
 
-    let vm = VM::new(program);
-    vm.run();
+    let output_pos = resume.as_ref().map_or(0, |snapshot| snapshot.output_pos);
+    let input = program_input(args.input, args.input_str);
+    let output: Option<Box<dyn Write>> = args.output.map(|path| {
+        let file = open_output(&path, output_pos)
+            .or_exit(Exit::Io, format_args!("could not open {}", path.display()));
+        Box::new(BufWriter::new(file)) as Box<dyn Write>
+    });
+
+    let options = RunOptions {
+        name,
+        src,
+        newline: args.newline,
+        record: args.record,
+        max_steps: args.max_steps,
+        snapshot: args.snapshot,
+        resume,
+        exit_cell: args.exit_cell,
+        profile: args.profile,
+        coverage: args.coverage,
+        lcov: args.lcov,
+        trace: args.trace.map(|path| TraceOptions {
+            path,
+            format: args.trace_format,
+            steps: args.trace_steps,
+            kinds: args.trace_ins,
+        }),
+        dump_tape: args.dump_tape,
+    };
+
+    match (input, output) {
+        // StdIO flushes stdout before blocking on stdin, so keep it for interactive use
+        (None, None) => execute(program, StdIO::new(), &options),
+        (input, output) => {
+            let input = input.unwrap_or_else(|| Box::new(io::stdin()));
+            let output = output.unwrap_or_else(|| Box::new(BufWriter::new(io::stdout().lock())));
+            execute(program, ReaderWriterIO::new(input, output), &options)
+        }
+    }
+}
+
+/// Run a `Program` with the given `IO`,
+/// translating input line breaks if `--newline` is set
+fn execute<IO: InputOutput>(program: Program, io: IO, options: &RunOptions) {
+    match options.newline {
+        Some(newline) => record(program, NewlineIO::new(io, newline), options),
+        None => record(program, io, options),
+    }
+}
+
+/// Run a `Program` with the given `IO`, skipping the input a program
+/// being resumed already read, and recording the session if `--record` is set
+fn record<IO: InputOutput>(program: Program, mut io: IO, options: &RunOptions) {
+    if let Some(snapshot) = &options.resume {
+        skip_input(&mut io, snapshot.input_pos);
+    }
+
+    match &options.record {
+        Some(path) => {
+            let log =
+                File::create(path).or_exit(Exit::Io, format_args!("could not create {}", path.display()));
+            let recorder = Recorder::new(io, BufWriter::new(log));
+            let steps = recorder.steps();
+            let mut vm = VM::new_with_io(program, recorder);
+            vm.share_steps(steps);
+            run_vm(vm, options);
+        }
+        None => run_vm(VM::new_with_io(program, io), options),
+    }
+}
+
+/// Run a `VM` to the end, exiting with an error message if it fails,
+/// or with the current cell if `--exit-cell` is set
+fn run_vm<IO: InputOutput>(mut vm: VM<IO>, options: &RunOptions) {
+    if let Some(snapshot) = &options.resume {
+        vm.restore(snapshot);
+    }
+
+    let mut profile = (options.profile || options.coverage.is_some()).then(|| Profile::new(vm.program()));
+    let mut tracer = options.trace.as_ref().map(open_trace);
+
+    // Ctrl-C is checked between chunks of steps, so the VM can stop cleanly
+    let interrupted = options.snapshot.as_ref().map(|_| catch_interrupt());
+    let chunk = match interrupted {
+        Some(_) => INTERRUPT_CHUNK,
+        None => u64::MAX,
+    };
+
+    let mut remaining = options.max_steps.unwrap_or(u64::MAX);
+    let result = loop {
+        let start = vm.steps();
+        let limit = remaining.min(chunk);
+        let result = match profile.is_none() && tracer.is_none() {
+            true => vm.run_steps(limit),
+            false => vm.run_observed(limit, &mut (&mut profile, &mut tracer)),
+        };
+        remaining -= vm.steps() - start;
+
+        let stop = interrupted
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::SeqCst));
+        if result.is_err() || vm.is_finished() || remaining == 0 || stop {
+            break result;
+        }
+    };
+
+    if let Some(tracer) = tracer {
+        tracer.finish().or_exit(Exit::Io, "could not write trace");
+    }
+    if let Some(profile) = &profile {
+        if options.profile {
+            profile
+                .report(
+                    vm.program(),
+                    options.src.as_deref(),
+                    PROFILE_TOP,
+                    &mut io::stderr(),
+                )
+                .or_exit(Exit::Io, "could not write profile");
+        }
+        if let Some(path) = &options.coverage {
+            write_coverage(path, vm.program(), profile, options);
+        }
+    }
+
+    if let Some(dump_tape) = &options.dump_tape {
+        write_tape(&vm, dump_tape);
+    }
+
+    if result.is_err() {
+        // the VM only flushes when it stops cleanly, so flush the output up to the error
+        let _ = vm.io_mut().flush();
+    }
+
+    match result {
+        Err(VMError::Io(err)) => fail(Exit::Io, format!("program input or output failed: {err}")),
+        Err(err) => fail(Exit::Runtime, format!("{}: {err}", location(&vm, options))),
+        Ok(()) if !vm.is_finished() => {
+            let saved = match &options.snapshot {
+                Some(path) => {
+                    write_file(path, vm.snapshot().to_bytes());
+                    format!(", saved snapshot to {}", path.display())
+                }
+                None => String::new(),
+            };
+
+            match remaining {
+                0 => fail(
+                    Exit::StepLimit,
+                    format!(
+                        "{}: step limit reached after {} steps{saved}",
+                        location(&vm, options),
+                        vm.steps()
+                    ),
+                ),
+                _ => fail(
+                    Exit::Interrupted,
+                    format!(
+                        "{}: interrupted after {} steps{saved}",
+                        location(&vm, options),
+                        vm.steps()
+                    ),
+                ),
+            }
+        }
+        Ok(()) if options.exit_cell => match vm.data().get(vm.ptr()) {
+            Some(&cell) => std::process::exit(cell.into()),
+            // the program ended, so there's no instruction to locate
+            None => {
+                let err = VMError::PointerOutOfBounds {
+                    ip: vm.ip(),
+                    ptr: vm.ptr() as isize,
+                };
+                fail(Exit::Runtime, format!("{}: {err}", options.name))
+            }
+        },
+        Ok(()) => {}
+    }
+}
+
+/// Load a snapshot for `--resume`, exiting with an error message if it's invalid
+fn load_snapshot(path: &Path) -> Snapshot {
+    let name = path.display();
+    let bytes = fs::read(path).or_exit(Exit::Io, format_args!("could not read {name}"));
+    Snapshot::from_bytes(&bytes).or_exit(Exit::Compile, format_args!("could not load snapshot from {name}"))
+}
+
+/// Skip the `count` bytes of input the program being resumed already read.
+/// They are read through the IO the VM runs with, so line breaks translated
+/// by `--newline` are counted the same way they were the first time.
+fn skip_input(io: &mut impl InputOutput, count: u64) {
+    for _ in 0..count {
+        let byte = io.getch().or_exit(Exit::Io, "could not skip input");
+        if byte.is_none() {
+            break;
+        }
+    }
+}
+
+/// Open the `--output` file, keeping the first `pos` bytes
+/// already written by the program being resumed, if any
+fn open_output(path: &Path, pos: u64) -> io::Result<File> {
+    let mut file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(pos == 0)
+        .open(path)?;
+    file.set_len(pos)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(file)
+}
+
+/// Catch Ctrl-C, returning a flag that is set once it's pressed.
+/// Pressing it again exits right away, in case the program is stuck on input.
+fn catch_interrupt() -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    let handler_flag = flag.clone();
+    ctrlc::set_handler(move || {
+        if handler_flag.swap(true, Ordering::SeqCst) {
+            std::process::exit(Exit::Interrupted as i32);
+        }
+    })
+    .or_exit(Exit::Io, "could not catch Ctrl-C");
+
+    flag
+}
+
+/// Tracer for `bfrs run --trace`
+#[cfg(feature = "trace")]
+type Trace = Tracer<BufWriter<File>>;
+
+/// Stand-in for the tracer when built without the `trace` feature,
+/// `open_trace` exits before one is ever created
+#[cfg(not(feature = "trace"))]
+enum Trace {}
+
+#[cfg(not(feature = "trace"))]
+impl brainfrick_rs::observer::Observer for Trace {
+    const STEPPED: bool = false;
+}
+
+#[cfg(not(feature = "trace"))]
+impl Trace {
+    fn finish(self) -> io::Result<()> {
+        match self {}
+    }
+}
+
+/// Create the trace file and tracer for `--trace`
+#[cfg(feature = "trace")]
+fn open_trace(options: &TraceOptions) -> Trace {
+    let format = options
+        .format
+        .parse()
+        .unwrap_or_else(|err: String| usage_error(&err));
+    let mut filter = Filter::default();
+    if let Some(steps) = &options.steps {
+        filter = filter.steps(trace::parse_steps(steps).unwrap_or_else(|err| usage_error(&err)));
+    }
+    if let Some(kinds) = &options.kinds {
+        filter = filter.kinds(kinds).unwrap_or_else(|err| usage_error(&err));
+    }
+
+    let name = options.path.display();
+    let file = File::create(&options.path).or_exit(Exit::Io, format_args!("could not create {name}"));
+    Tracer::new(BufWriter::new(file), format, filter)
+        .or_exit(Exit::Io, format_args!("could not write {name}"))
+}
+
+#[cfg(not(feature = "trace"))]
+fn open_trace(_options: &TraceOptions) -> Trace {
+    usage_error("`--trace` needs bfrs to be built with `--features trace`")
+}
+
+/// Write the coverage of a profiled run to `path`, and summarize it on stderr
+fn write_coverage(path: &Path, program: &Program, profile: &Profile, options: &RunOptions) {
+    let coverage = options
+        .src
+        .as_deref()
+        .and_then(|src| Coverage::new(src, program, profile))
+        .unwrap_or_else(|| fail(Exit::Usage, "coverage needs brainfuck source with a source map"));
+
+    let out = match options.lcov {
+        true => coverage.lcov(&options.name),
+        false => coverage.annotate(),
+    };
+    write_file(path, out);
+
+    let (ran, total) = coverage.summary();
+    let percent = match total {
+        0 => 100.0,
+        total => ran as f64 * 100.0 / total as f64,
+    };
+    eprintln!("coverage: {ran} of {total} commands ran ({percent:.1}%)");
+}
+
+/// Dump the memory of a stopped `VM` for `--dump-tape`
+fn write_tape<IO: InputOutput>(vm: &VM<IO>, dump_tape: &TapeDump) {
+    match dump_tape {
+        TapeDump::Hex => eprint!("{}", dump::hex(vm.data())),
+        TapeDump::Window(rows) => eprint!("{}", dump::window(vm.data(), vm.ptr(), *rows)),
+        TapeDump::Raw(path) => {
+            let mut file =
+                File::create(path).or_exit(Exit::Io, format_args!("could not create {}", path.display()));
+            dump::raw(vm.data(), &mut file)
+                .or_exit(Exit::Io, format_args!("could not write {}", path.display()));
+        }
+    }
+}
+
+/// Describe where a `VM` stopped, by line and column if the source is known
+fn location<IO: InputOutput>(vm: &VM<IO>, options: &RunOptions) -> String {
+    let span = vm.program().source_map.as_ref().map(|map| map[vm.ip()]);
+    match (span, &options.src) {
+        (Some(span), Some(src)) => {
+            let (line, col) = span.line_col(src);
+            format!("{}:{line}:{col}", options.name)
+        }
+        _ => options.name.clone(),
+    }
+}
+
+fn compile_bytecode(args: CompileArgs) {
+    let src = read_source(&args.file);
+    let mut program = compile_source(&args.file.display().to_string(), &src);
+
+    if args.strip {
+        program.source_map = None;
+    }
+
+    let output = args.output.unwrap_or_else(|| args.file.with_extension("bfc"));
+    write_file(&output, program.to_bytes());
+}
+
+fn emit(args: EmitArgs) {
+    let program = load_program(&args.file);
+
+    let out = match args.backend {
+        Backend::Asm => asm::emit(&program),
+    };
+
+    match args.output {
+        Some(path) => write_file(&path, out),
+        None => print!("{out}"),
+    }
+}
+
+fn check(args: CheckArgs) {
+    let mut failed = false;
+
+    for file in &args.files {
+        let name = file.display().to_string();
+        let src = read_source(file);
+
+        match compiler::check(&src) {
+            Ok(()) => println!("{name}: ok"),
+            Err(err) => {
+                eprintln!("error: {}", describe_error(&name, &src, err));
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(Exit::Compile as i32);
+    }
+}
+
+fn debug(args: DebugArgs) {
+    let (program, src) = load_source(&args.file);
+    let input = program_input(args.input, args.input_str).unwrap_or_else(|| Box::new(io::empty()));
+    let io = ReaderWriterIO::new(input, BufWriter::new(io::stdout()));
+
+    let mut debugger = Debugger::new(VM::new_with_io(program, io), src);
+    for breakpoint in args.breakpoint {
+        debugger.add_breakpoint(breakpoint);
+    }
+    for watchpoint in args.watch {
+        debugger.add_watchpoint(watchpoint);
+    }
+
+    debugger
+        .repl(io::stdin().lock(), io::stdout())
+        .or_exit(Exit::Io, "debugger input or output failed");
+}
+
+fn repl(args: ReplArgs) {
+    // stdin is only locked while reading each line, so programs can read from it too
+    let lines = std::iter::from_fn(|| {
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(line.trim_end_matches(['\r', '\n']).to_string())),
+            Err(err) => Some(Err(err)),
+        }
+    });
+
+    Repl::new(StdIO::new(), args.window)
+        .run(lines, io::stdout())
+        .or_exit(Exit::Io, "repl input or output failed");
+}
+
+fn replay(args: ReplayArgs) {
+    let program = load_program(&args.file);
+    let name = args.log.display();
+    let log = File::open(&args.log).or_exit(Exit::Io, format_args!("could not open {name}"));
+    let replay =
+        ReplayIO::from_log(BufReader::new(log)).or_exit(Exit::Io, format_args!("could not read {name}"));
+    let events = replay.remaining();
+    let steps = replay.steps();
+
+    let mut vm = VM::new_with_io(program, replay);
+    vm.share_steps(steps);
+    let result = vm.run().and_then(|()| Ok(vm.io().finish()?));
+    io::stdout()
+        .write_all(vm.io().output())
+        .or_exit(Exit::Io, "could not write output");
+
+    match result {
+        Ok(()) => eprintln!("replay matched {events} recorded events"),
+        Err(err) => fail(
+            Exit::Runtime,
+            format!("replay diverged after {} steps: {err}", vm.steps()),
+        ),
+    }
+}
+
+fn fmt(args: FmtArgs) {
+    let src = read_source(&args.file);
+
+    let out = match args.minify {
+        true => formatter::minify(&src),
+        false => formatter::pretty(&src, args.comments),
+    };
+
+    match args.in_place {
+        true => write_file(&args.file, out),
+        false => print!("{out}"),
+    }
+}
+
+fn visualize(args: VisualizeArgs) {
+    let (program, src) = load_source(&args.file);
+    let Some(src) = src else {
+        usage_error("`bfrs visualize` needs brainfuck source, not bytecode");
+    };
+    let input = program_input(args.input, args.input_str).map(|mut input| {
+        let mut bytes = Vec::new();
+        input
+            .read_to_end(&mut bytes)
+            .or_exit(Exit::Io, "could not read input");
+        bytes
+    });
+
+    let mut visualizer = Visualizer::new(program, src, input);
+    visualizer.set_speed(args.speed);
+    visualizer
+        .run()
+        .or_exit(Exit::Io, "could not draw to the terminal");
+
+    // the view is gone once it's closed, so leave the output behind
+    io::stdout()
+        .write_all(visualizer.output())
+        .or_exit(Exit::Io, "could not write output");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(b"a\nb\t\0\\\xff".to_vec(), unescape(r"a\nb\t\0\\\xff").unwrap());
+        assert_eq!("é".as_bytes().to_vec(), unescape("é").unwrap());
+    }
+
+    #[test]
+    fn test_unescape_invalid() {
+        assert!(unescape(r"\q").is_err());
+        assert!(unescape(r"\x4").is_err());
+        assert!(unescape(r"\xzz").is_err());
+        assert!(unescape("trailing\\").is_err());
+    }
 }
@@ -0,0 +1,282 @@
+//! The brainfuck VM `Observer`. Gets called back as a `VM`
+//! runs, to build tools like profilers, tracers and debuggers
+//! outside of the VM's dispatch loop. Observers are generic
+//! parameters of `VM::run_observed`, so callbacks an observer
+//! doesn't implement compile away. Unlimited runs also skip
+//! the step limit check, and the benches compare `VM::run`
+//! with the VM's original dispatch loop. Observers
+//! without `before` or `after` set `STEPPED` to `false`, so the
+//! VM doesn't keep its position up to date for them.
+
+use std::ops::ControlFlow;
+
+use crate::{io::InputOutput, vm::VM};
+
+/// Callbacks for the events of a running `VM`, all doing nothing by default.
+/// Callbacks returning `ControlFlow::Break` stop the VM, which can be run
+/// again to continue.
+pub trait Observer {
+    /// Whether `before` or `after` look at the VM, which then has
+    /// `ip` and `steps` kept up to date before each call to them
+    const STEPPED: bool = true;
+
+    /// Instruction `ip` is about to run, breaking stops the VM before it runs
+    fn before<IO: InputOutput>(&mut self, _vm: &VM<IO>, _ip: usize) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    /// Instruction `ip` has run, and the VM is ready to run the next one
+    fn after<IO: InputOutput>(&mut self, _vm: &VM<IO>, _ip: usize) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    /// Memory cell `cell` was written, even if its value didn't change
+    fn write(&mut self, _cell: usize, _before: u8, _after: u8) {}
+
+    /// A byte of input was read, or `None` at the end of input
+    fn input(&mut self, _byte: Option<u8>) {}
+
+    /// A byte was output
+    fn output(&mut self, _byte: u8) {}
+
+    /// The loop starting at instruction `ip` is entered, once per run of the loop
+    fn enter_loop(&mut self, _ip: usize) {}
+
+    /// The loop ending at instruction `ip` is left after its last iteration
+    fn exit_loop(&mut self, _ip: usize) {}
+}
+
+/// Observes nothing
+impl Observer for () {
+    const STEPPED: bool = false;
+}
+
+impl<O: Observer> Observer for &mut O {
+    const STEPPED: bool = O::STEPPED;
+
+    fn before<IO: InputOutput>(&mut self, vm: &VM<IO>, ip: usize) -> ControlFlow<()> {
+        (**self).before(vm, ip)
+    }
+
+    fn after<IO: InputOutput>(&mut self, vm: &VM<IO>, ip: usize) -> ControlFlow<()> {
+        (**self).after(vm, ip)
+    }
+
+    fn write(&mut self, cell: usize, before: u8, after: u8) {
+        (**self).write(cell, before, after)
+    }
+
+    fn input(&mut self, byte: Option<u8>) {
+        (**self).input(byte)
+    }
+
+    fn output(&mut self, byte: u8) {
+        (**self).output(byte)
+    }
+
+    fn enter_loop(&mut self, ip: usize) {
+        (**self).enter_loop(ip)
+    }
+
+    fn exit_loop(&mut self, ip: usize) {
+        (**self).exit_loop(ip)
+    }
+}
+
+/// Observes if `Some`
+impl<O: Observer> Observer for Option<O> {
+    const STEPPED: bool = O::STEPPED;
+
+    fn before<IO: InputOutput>(&mut self, vm: &VM<IO>, ip: usize) -> ControlFlow<()> {
+        match self {
+            Some(observer) => observer.before(vm, ip),
+            None => ControlFlow::Continue(()),
+        }
+    }
+
+    fn after<IO: InputOutput>(&mut self, vm: &VM<IO>, ip: usize) -> ControlFlow<()> {
+        match self {
+            Some(observer) => observer.after(vm, ip),
+            None => ControlFlow::Continue(()),
+        }
+    }
+
+    fn write(&mut self, cell: usize, before: u8, after: u8) {
+        if let Some(observer) = self {
+            observer.write(cell, before, after);
+        }
+    }
+
+    fn input(&mut self, byte: Option<u8>) {
+        if let Some(observer) = self {
+            observer.input(byte);
+        }
+    }
+
+    fn output(&mut self, byte: u8) {
+        if let Some(observer) = self {
+            observer.output(byte);
+        }
+    }
+
+    fn enter_loop(&mut self, ip: usize) {
+        if let Some(observer) = self {
+            observer.enter_loop(ip);
+        }
+    }
+
+    fn exit_loop(&mut self, ip: usize) {
+        if let Some(observer) = self {
+            observer.exit_loop(ip);
+        }
+    }
+}
+
+/// Observes with both, in order, stopping if either breaks
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    const STEPPED: bool = A::STEPPED || B::STEPPED;
+
+    fn before<IO: InputOutput>(&mut self, vm: &VM<IO>, ip: usize) -> ControlFlow<()> {
+        both(self.0.before(vm, ip), self.1.before(vm, ip))
+    }
+
+    fn after<IO: InputOutput>(&mut self, vm: &VM<IO>, ip: usize) -> ControlFlow<()> {
+        both(self.0.after(vm, ip), self.1.after(vm, ip))
+    }
+
+    fn write(&mut self, cell: usize, before: u8, after: u8) {
+        self.0.write(cell, before, after);
+        self.1.write(cell, before, after);
+    }
+
+    fn input(&mut self, byte: Option<u8>) {
+        self.0.input(byte);
+        self.1.input(byte);
+    }
+
+    fn output(&mut self, byte: u8) {
+        self.0.output(byte);
+        self.1.output(byte);
+    }
+
+    fn enter_loop(&mut self, ip: usize) {
+        self.0.enter_loop(ip);
+        self.1.enter_loop(ip);
+    }
+
+    fn exit_loop(&mut self, ip: usize) {
+        self.0.exit_loop(ip);
+        self.1.exit_loop(ip);
+    }
+}
+
+/// Break if either breaks
+fn both(a: ControlFlow<()>, b: ControlFlow<()>) -> ControlFlow<()> {
+    match (a, b) {
+        (ControlFlow::Continue(()), ControlFlow::Continue(())) => ControlFlow::Continue(()),
+        _ => ControlFlow::Break(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::compile, instruction::Instruction, io::MemoryIO};
+
+    use super::*;
+
+    /// Logs every event as text
+    #[derive(Default)]
+    struct Log(Vec<String>);
+
+    impl Observer for Log {
+        fn before<IO: InputOutput>(&mut self, _vm: &VM<IO>, ip: usize) -> ControlFlow<()> {
+            self.0.push(format!("before {ip}"));
+            ControlFlow::Continue(())
+        }
+
+        fn write(&mut self, cell: usize, before: u8, after: u8) {
+            self.0.push(format!("write {cell} {before}->{after}"));
+        }
+
+        fn input(&mut self, byte: Option<u8>) {
+            self.0.push(format!("input {byte:?}"));
+        }
+
+        fn output(&mut self, byte: u8) {
+            self.0.push(format!("output {byte}"));
+        }
+
+        fn enter_loop(&mut self, ip: usize) {
+            self.0.push(format!("enter {ip}"));
+        }
+
+        fn exit_loop(&mut self, ip: usize) {
+            self.0.push(format!("exit {ip}"));
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        // `[-]` becomes `Clear`, so only the outer loop is entered
+        let mut vm = VM::new_with_io(compile(",[.[-]]"), MemoryIO::new("a"));
+        let mut log = Log::default();
+        vm.run_observed(u64::MAX, &mut log).unwrap();
+
+        assert_eq!(
+            [
+                "before 0",
+                "input Some(97)",
+                "write 0 0->97",
+                "before 1",
+                "enter 1",
+                "before 2",
+                "output 97",
+                "before 3",
+                "write 0 97->0",
+                "before 4",
+                "exit 4",
+            ],
+            log.0.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_break() {
+        /// Stops before every `Out`
+        struct StopAtOut;
+
+        impl Observer for StopAtOut {
+            fn before<IO: InputOutput>(&mut self, vm: &VM<IO>, ip: usize) -> ControlFlow<()> {
+                match vm.program().instructions[ip] {
+                    Instruction::Out => ControlFlow::Break(()),
+                    _ => ControlFlow::Continue(()),
+                }
+            }
+        }
+
+        let mut vm = VM::new_with_io(compile("+.+."), MemoryIO::new(""));
+        let mut observers = (StopAtOut, Some(Log::default()));
+        vm.run_observed(u64::MAX, &mut observers).unwrap();
+        assert_eq!((1, 1), (vm.ip(), vm.steps()));
+        assert!(vm.io().output().is_empty());
+
+        // running again stops at the same instruction, so it has to be stepped over
+        vm.run_steps(1).unwrap();
+        vm.run_observed(u64::MAX, &mut observers).unwrap();
+        assert_eq!(b"\x01", vm.io().output());
+        assert_eq!(3, vm.ip());
+        // both observers see every event, even where one of them stops the VM
+        let log = observers.1.unwrap().0;
+        assert_eq!(
+            [
+                "before 0",
+                "write 0 0->1",
+                "before 1",
+                "before 2",
+                "write 0 1->2",
+                "before 3"
+            ],
+            log.as_slice()
+        );
+    }
+}
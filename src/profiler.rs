@@ -0,0 +1,240 @@
+//! The brainfuck `Profile`. Counts how many times each
+//! instruction of a `Program` runs, and reports the hottest
+//! loops and instructions by their position in the source.
+
+use std::{
+    io::{self, Write},
+    ops::ControlFlow,
+};
+
+use crate::{
+    compiler::Program,
+    formatter,
+    instruction::Instruction,
+    io::InputOutput,
+    observer::Observer,
+    vm::{VMError, VM},
+};
+
+/// Longest loop source shown in a report, longer loops are cut short
+const MAX_SNIPPET: usize = 32;
+
+/// Execution counts for each instruction of a `Program`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    counts: Vec<u64>,
+}
+
+/// Execution counts for one loop of the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopProfile {
+    /// Index of the `Loop` instruction, or of the instruction the loop was optimized into
+    pub ip: usize,
+    /// Times the loop was reached
+    pub entries: u64,
+    /// Times the loop body ran, unknown for loops the optimizer replaced
+    pub iterations: Option<u64>,
+    /// Instructions run inside the loop, including nested loops
+    pub steps: u64,
+    /// Instruction the optimizer replaced the loop with, if any
+    pub replaced_by: Option<Instruction>,
+}
+
+impl Profile {
+    /// Create an empty profile for the given Program
+    pub fn new(program: &Program) -> Self {
+        Profile {
+            counts: vec![0; program.instructions.len()],
+        }
+    }
+
+    /// Run a VM for at most `limit` instructions, counting each instruction it runs.
+    /// The VM must be running the program this profile was created for.
+    pub fn run<IO: InputOutput>(&mut self, vm: &mut VM<IO>, limit: u64) -> Result<(), VMError> {
+        vm.run_observed(limit, self)
+    }
+
+    /// Count one run of instruction `ip`
+    pub fn record(&mut self, ip: usize) {
+        self.counts[ip] += 1;
+    }
+
+    /// Times each instruction has run, by instruction index
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Total instructions run
+    pub fn steps(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Counts for each loop in the program, in program order.
+    /// Loops the optimizer replaced with a single instruction are included.
+    pub fn loops(&self, program: &Program) -> Vec<LoopProfile> {
+        program
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(ip, ins)| match ins {
+                Instruction::Loop => {
+                    let end = program.loop_map[ip];
+                    Some(LoopProfile {
+                        ip,
+                        entries: self.counts[ip],
+                        // the End instruction runs once at the end of each iteration
+                        iterations: Some(self.counts[end]),
+                        steps: self.counts[ip..=end].iter().sum(),
+                        replaced_by: None,
+                    })
+                }
+                Instruction::Clear | Instruction::CopyClear { .. } => Some(LoopProfile {
+                    ip,
+                    entries: self.counts[ip],
+                    iterations: None,
+                    steps: self.counts[ip],
+                    replaced_by: Some(ins.clone()),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Write the `top` hottest loops and instructions of `program`.
+    /// Pass the program's source, if known, to show where they came from.
+    pub fn report(
+        &self, program: &Program, src: Option<&str>, top: usize, out: &mut impl Write,
+    ) -> io::Result<()> {
+        let total = self.steps();
+        let share = |steps: u64| match total {
+            0 => 0.0,
+            total => steps as f64 * 100.0 / total as f64,
+        };
+
+        writeln!(out, "profile: {total} steps")?;
+
+        let mut loops = self.loops(program);
+        loops.retain(|l| l.entries > 0);
+        loops.sort_by_key(|l| std::cmp::Reverse(l.steps));
+
+        writeln!(out, "\nhottest loops:")?;
+        writeln!(
+            out,
+            "{:>7} {:>14} {:>12} {:>10}  loop",
+            "%", "steps", "iterations", "entries"
+        )?;
+        for l in loops.iter().take(top) {
+            let iterations = l.iterations.map_or("-".to_string(), |i| i.to_string());
+            let mut text = describe(program, src, l.ip, true);
+            if let Some(ins) = &l.replaced_by {
+                text.push_str(&format!(" optimized to {ins:?}"));
+            }
+
+            writeln!(
+                out,
+                "{:>6.2}% {:>14} {iterations:>12} {:>10}  {text}",
+                share(l.steps),
+                l.steps,
+                l.entries
+            )?;
+        }
+
+        let mut hottest: Vec<usize> = (0..self.counts.len()).filter(|&ip| self.counts[ip] > 0).collect();
+        hottest.sort_by_key(|&ip| std::cmp::Reverse(self.counts[ip]));
+
+        writeln!(out, "\nhottest instructions:")?;
+        writeln!(out, "{:>7} {:>14}  instruction", "%", "count")?;
+        for &ip in hottest.iter().take(top) {
+            let count = self.counts[ip];
+            let text = format!(
+                "{:?} {}",
+                program.instructions[ip],
+                describe(program, src, ip, false)
+            );
+            writeln!(out, "{:>6.2}% {count:>14}  {text}", share(count))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Counts each instruction once it has run
+impl Observer for Profile {
+    fn after<IO: InputOutput>(&mut self, _vm: &VM<IO>, ip: usize) -> ControlFlow<()> {
+        self.record(ip);
+        ControlFlow::Continue(())
+    }
+}
+
+/// Describe where instruction `ip` came from, by line and column
+/// if the source is known, optionally with the source of the loop it starts
+fn describe(program: &Program, src: Option<&str>, ip: usize, show_loop: bool) -> String {
+    let span = program
+        .source_map
+        .as_ref()
+        .map(|map| match program.instructions[ip] {
+            Instruction::Loop => map[ip].join(map[program.loop_map[ip]]),
+            _ => map[ip],
+        });
+    let (Some(span), Some(src)) = (span, src) else {
+        return format!("[{ip}]");
+    };
+
+    let (line, col) = span.line_col(src);
+    let mut text = format!("[{ip}] at {line}:{col}");
+    if show_loop {
+        let code = formatter::minify(&src[span.start..span.end]);
+        match code.char_indices().nth(MAX_SNIPPET) {
+            Some((end, _)) => text.push_str(&format!(" `{}...`", &code[..end])),
+            None => text.push_str(&format!(" `{code}`")),
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::compile, io::MemoryIO};
+
+    use super::*;
+
+    fn profile(src: &str) -> (Program, Profile) {
+        let program = compile(src);
+        let mut profile = Profile::new(&program);
+        let mut vm = VM::new_with_io(program.clone(), MemoryIO::new(""));
+
+        profile.run(&mut vm, u64::MAX).unwrap();
+        assert_eq!(vm.steps(), profile.steps());
+        (program, profile)
+    }
+
+    #[test]
+    fn test_loop_counts() {
+        // the outer loop runs 3 times, the inner loop 2 times per outer iteration
+        let (program, profile) = profile("+++[>++[>+>+<<-]<-]>>[-]");
+        let loops = profile.loops(&program);
+
+        assert_eq!(3, loops.len());
+        assert_eq!((1, Some(3)), (loops[0].entries, loops[0].iterations));
+        assert_eq!((3, Some(6)), (loops[1].entries, loops[1].iterations));
+        assert_eq!(Some(Instruction::Clear), loops[2].replaced_by);
+        assert_eq!(1, loops[2].steps);
+        // everything but the first `+++` and the last `>>` and `[-]` runs in the outer loop
+        assert_eq!(profile.steps() - 3, loops[0].steps);
+    }
+
+    #[test]
+    fn test_report() {
+        let src = "++[\n>+++[>+<-]<-]";
+        let (program, profile) = profile(src);
+
+        let mut out = Vec::new();
+        profile.report(&program, Some(src), 10, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with(&format!("profile: {} steps\n", profile.steps())));
+        assert!(out.contains("  [1] at 1:3 `[>+++[>+<-]<-]`\n"));
+        assert!(out.contains("  [4] at 2:5 `[>+<-]` optimized to CopyClear { mul: 1, offset: 1 }\n"));
+    }
+}
@@ -0,0 +1,132 @@
+//! The brainfuck `Repl`. Compiles and runs each entered line
+//! against the same `VM`, so memory and the memory pointer
+//! carry over from one line to the next.
+
+use std::io::{self, Write};
+
+use crate::{
+    compiler::{self, compile, CompileError},
+    io::InputOutput,
+    vm::VM,
+};
+
+/// Read-eval-print loop over a single `VM`
+pub struct Repl<IO: InputOutput> {
+    vm: VM<IO>,
+    /// Memory cells shown on either side of the pointer after each line
+    window: usize,
+}
+
+impl<IO: InputOutput> Repl<IO> {
+    /// Create a new REPL with empty memory, using the given `IO` for the program
+    pub fn new(io: IO, window: usize) -> Self {
+        Repl {
+            vm: VM::new_with_io(compile(""), io),
+            window,
+        }
+    }
+
+    /// The VM lines are run on
+    pub fn vm(&self) -> &VM<IO> {
+        &self.vm
+    }
+
+    /// Run lines until `lines` ends. Input with unclosed loops
+    /// continues on the next line, until the loops are balanced.
+    pub fn run(
+        &mut self, lines: impl IntoIterator<Item = io::Result<String>>, mut out: impl Write,
+    ) -> io::Result<()> {
+        let mut lines = lines.into_iter();
+        let mut pending = String::new();
+
+        loop {
+            let prompt = match pending.is_empty() {
+                true => "bf> ",
+                false => "... ",
+            };
+            write!(out, "{prompt}")?;
+            out.flush()?;
+
+            let Some(line) = lines.next() else {
+                return Ok(());
+            };
+            pending.push_str(&line?);
+            pending.push('\n');
+
+            match compiler::check(&pending) {
+                Ok(()) => {}
+                // wait for the rest of the loop
+                Err(CompileError::UnmatchedLoop(_)) => continue,
+                Err(err) => {
+                    let (line, col) = err.span().line_col(&pending);
+                    writeln!(out, "error: {line}:{col}: {err}")?;
+                    pending.clear();
+                    continue;
+                }
+            }
+
+            // a line that fails is undone, rather than left
+            // for every later line to run into again
+            let before = self.vm.snapshot();
+            self.vm.append(compile(&std::mem::take(&mut pending)));
+            if let Err(err) = self.vm.run() {
+                writeln!(out, "program error: {err}")?;
+                self.vm.restore(&before);
+            }
+
+            writeln!(out, "{:width$}", self.vm, width = self.window)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::MemoryIO;
+
+    use super::*;
+
+    fn repl(lines: &str) -> (Repl<MemoryIO>, String) {
+        let mut repl = Repl::new(MemoryIO::new(""), 2);
+        let mut out = Vec::new();
+
+        repl.run(lines.lines().map(|line| Ok(line.to_string())), &mut out)
+            .unwrap();
+        (repl, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_persistent_tape() {
+        let (repl, out) = repl("+++\n>++\n<[->+<]>.\n");
+
+        assert_eq!(b"\x05", repl.vm().io().output());
+        assert!(out.contains("\tptr: 1\n\tmem[0..4]: [3, (2), 0, 0, ]\n"));
+        assert!(out.ends_with("\tptr: 1\n\tmem[0..4]: [0, (5), 0, 0, ]\n}\nbf> "));
+    }
+
+    #[test]
+    fn test_multi_line_loops() {
+        let (repl, out) = repl("++++[\n>+\n<-\n]>.\n");
+
+        assert_eq!(b"\x04", repl.vm().io().output());
+        assert_eq!(3, out.matches("... ").count());
+    }
+
+    #[test]
+    fn test_recover_from_error() {
+        let (repl, out) = repl("+<.\n>+.\n");
+
+        assert_eq!(1, out.matches("program error: memory pointer").count());
+        // the failed line's changes are undone, and the next line runs
+        assert_eq!(b"\x01", repl.vm().io().output());
+        assert!(out.ends_with("\tptr: 1\n\tmem[0..4]: [0, (1), 0, 0, ]\n}\nbf> "));
+    }
+
+    #[test]
+    fn test_unmatched_end() {
+        let (repl, out) = repl("+]\n+.\n");
+
+        assert!(out.contains("error: 1:2: unmatched `]`\n"));
+        // the bad line is skipped entirely
+        assert_eq!(b"\x01", repl.vm().io().output());
+    }
+}
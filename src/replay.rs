@@ -0,0 +1,346 @@
+//! Session recording and replay.
+//! `Recorder` wraps an `InputOutput`, logging every byte a program
+//! reads and writes with the step it happened at, and `ReplayIO` feeds
+//! the recorded input back while checking the program reads and writes
+//! the same. Both take the step from a `StepCounter` the VM shares.
+//!
+//! Logs have one event per line, `<step> in <byte>`, `<step> in eof`
+//! or `<step> out <byte>`, with bytes written in decimal.
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    io::{self, BufRead, ErrorKind, Write},
+    str::FromStr,
+};
+
+use crate::io::{InputOutput, StepCounter};
+
+/// A single recorded IO event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A byte was read, or `None` at the end of input
+    In { step: u64, byte: Option<u8> },
+    /// A byte was written
+    Out { step: u64, byte: u8 },
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::In {
+                step,
+                byte: Some(byte),
+            } => write!(f, "{step} in {byte}"),
+            Event::In { step, byte: None } => write!(f, "{step} in eof"),
+            Event::Out { step, byte } => write!(f, "{step} out {byte}"),
+        }
+    }
+}
+
+impl FromStr for Event {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid event `{s}`");
+        let mut parts = s.split_whitespace();
+        let (Some(step), Some(kind), Some(byte), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+
+        let step = step.parse().map_err(|_| invalid())?;
+        let parse_byte = |byte: &str| byte.parse().map_err(|_| invalid());
+        match (kind, byte) {
+            ("in", "eof") => Ok(Event::In { step, byte: None }),
+            ("in", byte) => Ok(Event::In {
+                step,
+                byte: Some(parse_byte(byte)?),
+            }),
+            ("out", byte) => Ok(Event::Out {
+                step,
+                byte: parse_byte(byte)?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// InputOutput wrapper recording every byte the program reads
+/// and writes through the inner `IO` to a log, with the step it
+/// happened at. Give the VM its `steps` counter with `share_steps`.
+pub struct Recorder<IO: InputOutput, W: Write> {
+    inner: IO,
+    log: W,
+    steps: StepCounter,
+}
+
+impl<IO: InputOutput, W: Write> Recorder<IO, W> {
+    /// Wrap `inner`, logging its reads and writes to `log`
+    pub fn new(inner: IO, log: W) -> Self {
+        Recorder {
+            inner,
+            log,
+            steps: StepCounter::new(),
+        }
+    }
+
+    /// Counter for the VM to share its steps through
+    pub fn steps(&self) -> StepCounter {
+        self.steps.clone()
+    }
+
+    /// Unwrap this wrapper, returning the inner `IO` and the log
+    pub fn into_inner(self) -> (IO, W) {
+        (self.inner, self.log)
+    }
+
+    fn record(&mut self, event: Event) -> io::Result<()> {
+        writeln!(self.log, "{event}")
+    }
+}
+
+impl<IO: InputOutput, W: Write> InputOutput for Recorder<IO, W> {
+    fn getch(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.inner.getch()?;
+        self.record(Event::In {
+            step: self.steps.get(),
+            byte,
+        })?;
+        Ok(byte)
+    }
+
+    fn print(&mut self, byte: u8) -> io::Result<()> {
+        self.inner.print(byte)?;
+        self.record(Event::Out {
+            step: self.steps.get(),
+            byte,
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.log.flush()
+    }
+}
+
+/// InputOutput implementor replaying a recorded session. The program
+/// reads the recorded input, and each read or write is checked against
+/// the recording, failing with an `InvalidData` error where it diverges.
+/// Give the VM its `steps` counter with `share_steps`.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayIO {
+    events: VecDeque<Event>,
+    steps: StepCounter,
+    output: Vec<u8>,
+    /// Where the program diverged from the recording
+    diverged: Option<String>,
+}
+
+impl ReplayIO {
+    pub fn new(events: impl IntoIterator<Item = Event>) -> Self {
+        ReplayIO {
+            events: events.into_iter().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Read a log written by `Recorder`, failing with an
+    /// `InvalidData` error on a line that isn't an event
+    pub fn from_log(log: impl BufRead) -> io::Result<Self> {
+        let mut events = Vec::new();
+        for (number, line) in log.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let event = line.parse().map_err(|err| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("bad replay log: line {}: {err}", number + 1),
+                )
+            })?;
+            events.push(event);
+        }
+
+        Ok(ReplayIO::new(events))
+    }
+
+    /// Counter for the VM to share its steps through
+    pub fn steps(&self) -> StepCounter {
+        self.steps.clone()
+    }
+
+    /// Output written so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Recorded events not yet replayed
+    pub fn remaining(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Check the replay once the program has ended, failing with an
+    /// `InvalidData` error if it diverged or left events unreplayed
+    pub fn finish(&self) -> io::Result<()> {
+        match (&self.diverged, self.events.front()) {
+            (Some(msg), _) => Err(diverged(msg.clone())),
+            (None, Some(next)) => Err(diverged(format!("program ended before `{next}`"))),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Take the next event if `matches` accepts it, otherwise failing with
+    /// `actual` as what the program did, and from then on failing every call
+    fn next(&mut self, matches: impl FnOnce(&Event) -> bool, actual: impl Display) -> io::Result<Event> {
+        if self.diverged.is_none() {
+            self.diverged = match self.events.pop_front() {
+                Some(expected) if matches(&expected) => return Ok(expected),
+                Some(expected) => Some(format!("expected `{expected}`, got `{actual}`")),
+                None => Some(format!("unexpected `{actual}` after the end of the recording")),
+            };
+        }
+
+        Err(diverged(self.diverged.clone().unwrap_or_default()))
+    }
+}
+
+impl InputOutput for ReplayIO {
+    fn getch(&mut self) -> io::Result<Option<u8>> {
+        // the program reads whatever byte was recorded
+        let step = self.steps.get();
+        let matches = |event: &Event| matches!(event, Event::In { step: at, .. } if *at == step);
+        match self.next(matches, format_args!("{step} in"))? {
+            Event::In { byte, .. } => Ok(byte),
+            Event::Out { .. } => unreachable!("only an `In` matches a read"),
+        }
+    }
+
+    fn print(&mut self, byte: u8) -> io::Result<()> {
+        self.output.push(byte);
+        let actual = Event::Out {
+            step: self.steps.get(),
+            byte,
+        };
+        self.next(|event| *event == actual, actual).map(|_| ())
+    }
+}
+
+/// Error for a replay that diverged from its recording
+fn diverged(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::compile,
+        decoded::DecodedVM,
+        io::MemoryIO,
+        vm::{VMError, VM},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_event_round_trip() {
+        let events = [
+            Event::In {
+                step: 0,
+                byte: Some(65),
+            },
+            Event::In { step: 7, byte: None },
+            Event::Out { step: 12, byte: 255 },
+        ];
+
+        for event in events {
+            assert_eq!(Ok(event), event.to_string().parse());
+        }
+        assert!("1 in".parse::<Event>().is_err());
+        assert!("1 out 256".parse::<Event>().is_err());
+        assert!("x in 1".parse::<Event>().is_err());
+    }
+
+    /// Replay `log` on `src`, returning the program's output and the replay's result
+    fn replay(src: &str, log: &[u8]) -> (Vec<u8>, io::Result<()>) {
+        let replay = ReplayIO::from_log(log).unwrap();
+        let steps = replay.steps();
+        let mut vm = VM::new_with_io(compile(src), replay);
+        vm.share_steps(steps);
+        let result = vm.run().map_err(|err| match err {
+            VMError::Io(err) => err,
+            err => panic!("{err}"),
+        });
+        let result = result.and_then(|()| vm.io().finish());
+        (vm.io().output().to_vec(), result)
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let src = ",[.,]+.";
+        let recorder = Recorder::new(MemoryIO::new("hi"), Vec::new());
+        let steps = recorder.steps();
+        let mut vm = VM::new_with_io(compile(src), recorder);
+        vm.share_steps(steps);
+        vm.run().unwrap();
+        let (io, log) = vm.into_io().into_inner();
+        assert_eq!(b"hi\x01", io.output());
+
+        let text = String::from_utf8(log.clone()).unwrap();
+        assert_eq!(
+            "0 in 104\n2 out 104\n3 in 105\n5 out 105\n6 in eof\n9 out 1\n",
+            text
+        );
+
+        let (output, result) = replay(src, &log);
+        assert_eq!(b"hi\x01", output.as_slice());
+        result.unwrap();
+
+        // a replay paused along the way only fails if it diverges
+        let replay = ReplayIO::from_log(&log[..]).unwrap();
+        let steps = replay.steps();
+        let mut vm = VM::new_with_io(compile(src), replay);
+        vm.share_steps(steps);
+        while !vm.is_finished() {
+            vm.run_steps(1).unwrap();
+        }
+        vm.io().finish().unwrap();
+
+        // the decoded VM shares its steps too
+        let recorder = Recorder::new(MemoryIO::new("hi"), Vec::new());
+        let steps = recorder.steps();
+        let mut vm = DecodedVM::new_with_io(compile(src), recorder);
+        vm.share_steps(steps);
+        vm.run().unwrap();
+        assert_eq!(log, vm.into_io().into_inner().1);
+    }
+
+    #[test]
+    fn test_replay_diverged() {
+        let log = b"0 in 104\n2 out 104\n3 in eof\n";
+        let diverged = |result: io::Result<()>| {
+            assert_eq!(ErrorKind::InvalidData, result.unwrap_err().kind());
+        };
+
+        // different output, stopping where it diverged
+        let (output, result) = replay(",+.,.", log);
+        assert_eq!(b"i", output.as_slice());
+        diverged(result);
+
+        // input at a different step
+        diverged(replay("+,", log).1);
+
+        // ends early
+        diverged(replay(",.", log).1);
+    }
+
+    #[test]
+    fn test_bad_log() {
+        let err = ReplayIO::from_log(&b"0 in 104\n2 out\n"[..]).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert_eq!("bad replay log: line 2: invalid event `2 out`", err.to_string());
+    }
+}
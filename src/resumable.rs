@@ -0,0 +1,209 @@
+//! The resumable `ResumableVM`. Runs a `Program` as a state
+//! machine that returns whenever it needs input or has output,
+//! instead of blocking on IO, so it can be driven by any
+//! async runtime or event loop without threads.
+
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind},
+};
+
+use crate::{
+    compiler::Program,
+    io::InputOutput,
+    vm::{VMError, VM},
+};
+
+/// Output is handed back once this many bytes are buffered
+const OUTPUT_CHUNK: usize = 4096;
+
+/// Why a `ResumableVM` returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum State {
+    /// The program is waiting for input, provide it with `push_input`
+    /// or `close_input` before resuming
+    NeedsInput,
+    /// The program wrote these bytes
+    Output(Vec<u8>),
+    /// The program has ended, and all output has been returned
+    Finished,
+}
+
+/// InputOutput implementor for `ResumableVM`.
+/// Reading with no input available returns `WouldBlock`,
+/// as does writing with a full output buffer.
+#[derive(Debug, Clone, Default)]
+struct PendingIO {
+    input: VecDeque<u8>,
+    /// Whether the input has ended, once the buffered input runs out
+    closed: bool,
+    output: Vec<u8>,
+}
+
+impl InputOutput for PendingIO {
+    fn getch(&mut self) -> io::Result<Option<u8>> {
+        match self.input.pop_front() {
+            Some(byte) => Ok(Some(byte)),
+            None if self.closed => Ok(None),
+            None => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn print(&mut self, byte: u8) -> io::Result<()> {
+        if self.output.len() >= OUTPUT_CHUNK {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        self.output.push(byte);
+        Ok(())
+    }
+}
+
+/// Brainfuck VM driven one `State` at a time.
+///
+/// Call `resume` until it returns `State::Finished`, passing any
+/// `State::Output` along and answering `State::NeedsInput`
+/// with `push_input` or `close_input`.
+#[derive(Debug, Clone)]
+pub struct ResumableVM {
+    vm: VM<PendingIO>,
+}
+
+impl ResumableVM {
+    /// Create a new resumable VM to execute the given Program
+    pub fn new(program: Program) -> Self {
+        ResumableVM {
+            vm: VM::new_with_io(program, PendingIO::default()),
+        }
+    }
+
+    /// Run until the program needs input, has output or ends
+    pub fn resume(&mut self) -> Result<State, VMError> {
+        // the limit is never reached, so there's always a state
+        Ok(self.resume_steps(u64::MAX)?.unwrap_or(State::NeedsInput))
+    }
+
+    /// Run like `resume`, but for at most `limit` instructions.
+    /// Returns `None` if the limit was reached first, with no output.
+    pub fn resume_steps(&mut self, limit: u64) -> Result<Option<State>, VMError> {
+        let blocked = match self.vm.run_steps(limit) {
+            Ok(()) => false,
+            Err(VMError::Io(err)) if err.kind() == ErrorKind::WouldBlock => true,
+            Err(err) => return Err(err),
+        };
+
+        let io = self.vm.io_mut();
+        if !io.output.is_empty() {
+            return Ok(Some(State::Output(std::mem::take(&mut io.output))));
+        }
+
+        match (self.vm.is_finished(), blocked) {
+            (true, _) => Ok(Some(State::Finished)),
+            (false, true) => Ok(Some(State::NeedsInput)),
+            (false, false) => Ok(None),
+        }
+    }
+
+    /// Provide more input to the program
+    pub fn push_input(&mut self, bytes: impl AsRef<[u8]>) {
+        self.vm.io_mut().input.extend(bytes.as_ref());
+    }
+
+    /// End the input, once the input already pushed has been read
+    /// the program reads the end of input
+    pub fn close_input(&mut self) {
+        self.vm.io_mut().closed = true;
+    }
+
+    /// Number of instructions executed so far
+    pub fn steps(&self) -> u64 {
+        self.vm.steps()
+    }
+
+    /// The underlying VM, to inspect its memory and position
+    pub fn vm(&self) -> &VM<impl InputOutput> {
+        &self.vm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::compile;
+
+    use super::*;
+
+    /// Drive a `ResumableVM` like an event loop would,
+    /// feeding one chunk of input each time it's needed
+    fn drive(vm: &mut ResumableVM, mut chunks: VecDeque<&str>) -> Vec<State> {
+        let mut states = Vec::new();
+        loop {
+            let state = vm.resume().unwrap();
+            match &state {
+                State::NeedsInput => match chunks.pop_front() {
+                    Some(chunk) => vm.push_input(chunk),
+                    None => vm.close_input(),
+                },
+                State::Output(_) => {}
+                State::Finished => return states,
+            }
+            states.push(state);
+        }
+    }
+
+    #[test]
+    fn test_resume_echo() {
+        // prompt, then echo input until the end of input
+        let mut vm = ResumableVM::new(compile("++++++[>++++++++++<-]>++.,[.,]"));
+        let states = drive(&mut vm, VecDeque::from(["hi", "!"]));
+
+        assert_eq!(
+            vec![
+                State::Output(b">".to_vec()),
+                State::NeedsInput,
+                State::Output(b"hi".to_vec()),
+                State::NeedsInput,
+                State::Output(b"!".to_vec()),
+                State::NeedsInput,
+            ],
+            states
+        );
+        assert_eq!(State::Finished, vm.resume().unwrap());
+    }
+
+    #[test]
+    fn test_resume_steps() {
+        let mut vm = ResumableVM::new(compile("+++.,"));
+
+        assert_eq!(None, vm.resume_steps(1).unwrap());
+        assert_eq!(1, vm.steps());
+        assert_eq!(Some(State::Output(b"\x03".to_vec())), vm.resume_steps(5).unwrap());
+        assert_eq!(Some(State::NeedsInput), vm.resume_steps(5).unwrap());
+        vm.close_input();
+        assert_eq!(Some(State::Finished), vm.resume_steps(5).unwrap());
+    }
+
+    #[test]
+    fn test_resume_output_chunks() {
+        // prints 100 down to 1, 50 times over, for more output than fits in a chunk
+        let mut vm = ResumableVM::new(compile(
+            "++++++++++[>+++++<-]>[>++++++++++[>++++++++++<-]>[.-]<<-]",
+        ));
+        let out: Vec<u8> = (0..50).flat_map(|_| (1..=100).rev()).collect();
+
+        let mut output = Vec::new();
+        let mut chunks = 0;
+        for state in drive(&mut vm, VecDeque::new()) {
+            match state {
+                State::Output(bytes) => {
+                    assert!(bytes.len() <= OUTPUT_CHUNK);
+                    output.extend(bytes);
+                    chunks += 1;
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+
+        assert_eq!(2, chunks);
+        assert_eq!(out, output);
+    }
+}
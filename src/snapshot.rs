@@ -0,0 +1,199 @@
+//! The brainfuck VM `Snapshot`. Captures everything needed to
+//! resume a `VM` later, in another process if need be, including
+//! the program itself so a snapshot file stands on its own.
+//!
+//! Layout (all integers little-endian):
+//! ```text
+//! magic     4 bytes   b"BFSN"
+//! version   u16
+//! ip        u32       instruction to run next
+//! ptr       u64       memory pointer
+//! steps     u64       instructions run so far
+//! input     u64       bytes of input read so far
+//! output    u64       bytes of output written so far
+//! tape len  u32       memory cells stored, trailing zero cells are left out
+//! tape      len bytes
+//! program   the program as bytecode, until the end of the file
+//! ```
+
+use std::fmt::Display;
+
+use crate::{
+    bytecode::{put_u32, BytecodeError, Reader},
+    compiler::Program,
+    vm::MEM,
+};
+
+/// Magic bytes identifying a snapshot file
+pub const MAGIC: &[u8; 4] = b"BFSN";
+
+/// Current snapshot format version
+pub const VERSION: u16 = 1;
+
+/// State of a `VM`, taken with `VM::snapshot`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub program: Program,
+    pub data: Box<[u8; MEM]>,
+    pub ptr: usize,
+    pub ip: usize,
+    pub steps: u64,
+    /// Bytes of input the program has read
+    pub input_pos: u64,
+    /// Bytes of output the program has written
+    pub output_pos: u64,
+}
+
+/// Errors produced when loading an invalid snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// Data does not start with `MAGIC`
+    BadMagic,
+    /// Data was written by an unsupported format version
+    UnsupportedVersion(u16),
+    /// The state doesn't fit the program or memory
+    InvalidState,
+    /// The data or the program in it is malformed
+    Bytecode(BytecodeError),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not a brainfrick snapshot file"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {v}"),
+            SnapshotError::InvalidState => write!(f, "snapshot state does not fit its program"),
+            SnapshotError::Bytecode(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<BytecodeError> for SnapshotError {
+    fn from(err: BytecodeError) -> Self {
+        SnapshotError::Bytecode(err)
+    }
+}
+
+impl Snapshot {
+    /// Serialize this `Snapshot`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let tape_len = self
+            .data
+            .iter()
+            .rposition(|&cell| cell != 0)
+            .map_or(0, |last| last + 1);
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        put_u32(&mut out, self.ip);
+        for value in [self.ptr as u64, self.steps, self.input_pos, self.output_pos] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        put_u32(&mut out, tape_len);
+        out.extend_from_slice(&self.data[..tape_len]);
+        out.extend_from_slice(&self.program.to_bytes());
+        out
+    }
+
+    /// Deserialize a `Snapshot`, validating its program
+    /// and that the state fits it.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut reader = Reader::new(bytes);
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let ip = reader.u32()? as usize;
+        let ptr = reader.u64()? as usize;
+        let steps = reader.u64()?;
+        let input_pos = reader.u64()?;
+        let output_pos = reader.u64()?;
+
+        let tape_len = reader.u32()? as usize;
+        if tape_len > MEM {
+            return Err(SnapshotError::InvalidState);
+        }
+        let mut data = Box::new([0; MEM]);
+        data[..tape_len].copy_from_slice(reader.take(tape_len)?);
+
+        let program = Program::from_bytes(reader.rest())?;
+        if ip > program.instructions.len() {
+            return Err(SnapshotError::InvalidState);
+        }
+
+        Ok(Snapshot {
+            program,
+            data,
+            ptr,
+            ip,
+            steps,
+            input_pos,
+            output_pos,
+        })
+    }
+
+    /// Returns true if the given bytes look like a snapshot
+    pub fn is_snapshot(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::compile, io::MemoryIO, vm::VM};
+
+    use super::*;
+
+    #[test]
+    fn test_resume() {
+        let src = include_str!("../samples/sierpinski.bf");
+        let out = include_str!("../samples/out/sierpinski.txt");
+
+        let mut vm = VM::new_with_io(compile(src), MemoryIO::new(""));
+        vm.run_steps(10_000).unwrap();
+        let written = vm.io().output().to_vec();
+        // stopped partway through the output
+        assert!(!written.is_empty() && !vm.is_finished());
+        let snapshot = Snapshot::from_bytes(&vm.snapshot().to_bytes()).unwrap();
+        assert_eq!(vm.snapshot(), snapshot);
+
+        // a fresh VM picks up where the first one stopped
+        let mut resumed = VM::new_with_io(compile(""), MemoryIO::new(""));
+        resumed.restore(&snapshot);
+        resumed.run().unwrap();
+
+        assert_eq!(written.len() as u64, snapshot.output_pos);
+        assert_eq!(out.as_bytes(), [written, resumed.io().output().to_vec()].concat());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut vm = VM::new_with_io(compile(",[.,]"), MemoryIO::new("ab"));
+        vm.run_steps(4).unwrap();
+        let snapshot = vm.snapshot();
+        assert_eq!((2, 1), (snapshot.input_pos, snapshot.output_pos));
+
+        let bytes = snapshot.to_bytes();
+        assert_eq!(Err(SnapshotError::BadMagic), Snapshot::from_bytes(&bytes[1..]));
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Bytecode(_))
+        ));
+
+        let mut bad_ip = snapshot.clone();
+        bad_ip.ip = 100;
+        assert_eq!(
+            Err(SnapshotError::InvalidState),
+            Snapshot::from_bytes(&bad_ip.to_bytes())
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! The brainfuck `Tracer`. Logs each instruction a `VM` runs,
+//! with the memory pointer and current cell after it ran, to
+//! find where a program's behavior goes wrong. Only built with
+//! the `trace` feature.
+//!
+//! `Format::Json` writes one object per line:
+//! ```text
+//! {"step":12,"ip":3,"ins":"Alt(2)","ptr":0,"cell":2}
+//! ```
+//! `cell` is `null` if the pointer is outside of memory.
+//!
+//! `Format::Binary` writes a header followed by fixed size records,
+//! all integers little-endian, opcodes as in the `bytecode` module:
+//! ```text
+//! magic     4 bytes   b"BFTR"
+//! version   u16
+//! records   22 bytes each:
+//!   step    u64
+//!   ip      u32
+//!   opcode  u8
+//!   ptr     i64
+//!   cell    u8        0 if the pointer is outside of memory
+//! ```
+
+use std::{
+    io::{self, Write},
+    ops::{ControlFlow, Range},
+    str::FromStr,
+};
+
+use crate::{
+    bytecode,
+    instruction::Instruction,
+    io::InputOutput,
+    observer::Observer,
+    vm::{VMError, VM},
+};
+
+/// Magic bytes identifying a binary trace
+pub const MAGIC: &[u8; 4] = b"BFTR";
+
+/// Current binary trace format version
+pub const VERSION: u16 = 1;
+
+/// Names of the instruction kinds, by opcode
+const KINDS: [&str; 8] = ["shift", "alt", "out", "in", "loop", "end", "clear", "copyclear"];
+
+/// How a `Tracer` writes each instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per line
+    Json,
+    /// Fixed size binary records
+    Binary,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "binary" => Ok(Format::Binary),
+            other => Err(format!(
+                "unknown trace format `{other}`, expected one of: json, binary"
+            )),
+        }
+    }
+}
+
+/// Which instructions a `Tracer` logs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    /// Steps to log, counting from the first instruction of the program
+    pub steps: Range<u64>,
+    /// Bit set of the opcodes to log
+    kinds: u8,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            steps: 0..u64::MAX,
+            kinds: u8::MAX,
+        }
+    }
+}
+
+impl Filter {
+    /// Only log the given steps
+    pub fn steps(mut self, steps: Range<u64>) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// Only log the instruction kinds named in a comma separated list,
+    /// like `in,out,loop`. Kinds are named after `Instruction`s, in lowercase.
+    pub fn kinds(mut self, names: &str) -> Result<Self, String> {
+        self.kinds = 0;
+        for name in names.split(',').map(str::trim) {
+            let opcode = KINDS.iter().position(|kind| *kind == name).ok_or_else(|| {
+                format!(
+                    "unknown instruction kind `{name}`, expected any of: {}",
+                    KINDS.join(", ")
+                )
+            })?;
+            self.kinds |= 1 << opcode;
+        }
+
+        Ok(self)
+    }
+
+    fn matches(&self, step: u64, ins: &Instruction) -> bool {
+        self.steps.contains(&step) && self.kinds & (1 << bytecode::opcode(ins)) != 0
+    }
+}
+
+/// Parse a range of steps like `100..200`, `100..` or `..200`
+pub fn parse_steps(s: &str) -> Result<Range<u64>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("invalid step range `{s}`, expected like `100..200`"))?;
+    let parse = |bound: &str, default| match bound {
+        "" => Ok(default),
+        bound => bound
+            .parse()
+            .map_err(|_| format!("invalid step `{bound}` in `{s}`")),
+    };
+
+    Ok(parse(start, 0)?..parse(end, u64::MAX)?)
+}
+
+/// Writes a trace of the instructions a `VM` runs
+pub struct Tracer<W: Write> {
+    out: W,
+    format: Format,
+    filter: Filter,
+    /// First error writing the trace, reported by `finish`
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Tracer<W> {
+    /// Create a new tracer writing to `out`
+    pub fn new(mut out: W, format: Format, filter: Filter) -> io::Result<Self> {
+        if format == Format::Binary {
+            out.write_all(MAGIC)?;
+            out.write_all(&VERSION.to_le_bytes())?;
+        }
+
+        Ok(Tracer {
+            out,
+            format,
+            filter,
+            error: None,
+        })
+    }
+
+    /// Run a VM for at most `limit` instructions, logging each instruction it runs
+    pub fn run<IO: InputOutput>(&mut self, vm: &mut VM<IO>, limit: u64) -> Result<(), VMError> {
+        vm.run_observed(limit, self)
+    }
+
+    /// Log a run of instruction `ip`, given the VM after it ran
+    pub fn record<IO: InputOutput>(&mut self, vm: &VM<IO>, ip: usize) {
+        // counted by the VM, so traces of resumed VMs carry on from their step
+        let step = vm.steps() - 1;
+        let ins = &vm.program().instructions[ip];
+        if self.error.is_some() || !self.filter.matches(step, ins) {
+            return;
+        }
+
+        let ptr = vm.ptr();
+        let cell = vm.data().get(ptr).copied();
+        let result = match self.format {
+            Format::Json => {
+                let cell = cell.map_or("null".to_string(), |cell| cell.to_string());
+                writeln!(
+                    self.out,
+                    r#"{{"step":{step},"ip":{ip},"ins":"{ins:?}","ptr":{},"cell":{cell}}}"#,
+                    ptr as isize
+                )
+            }
+            Format::Binary => {
+                let mut record = [0; 22];
+                record[0..8].copy_from_slice(&step.to_le_bytes());
+                record[8..12].copy_from_slice(&(ip as u32).to_le_bytes());
+                record[12] = bytecode::opcode(ins);
+                record[13..21].copy_from_slice(&(ptr as i64).to_le_bytes());
+                record[21] = cell.unwrap_or(0);
+                self.out.write_all(&record)
+            }
+        };
+
+        self.error = result.err();
+    }
+
+    /// Flush the trace, returning the writer or the first error writing to it
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Logs each instruction once it has run
+impl<W: Write> Observer for Tracer<W> {
+    fn after<IO: InputOutput>(&mut self, vm: &VM<IO>, ip: usize) -> ControlFlow<()> {
+        self.record(vm, ip);
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::compile, io::MemoryIO};
+
+    use super::*;
+
+    fn trace(src: &str, format: Format, filter: Filter) -> Vec<u8> {
+        let mut vm = VM::new_with_io(compile(src), MemoryIO::new(""));
+        let mut tracer = Tracer::new(Vec::new(), format, filter).unwrap();
+
+        tracer.run(&mut vm, u64::MAX).unwrap();
+        tracer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_json() {
+        let out = trace("++>+<[-]", Format::Json, Filter::default());
+
+        assert_eq!(
+            concat!(
+                r#"{"step":0,"ip":0,"ins":"Alt(2)","ptr":0,"cell":2}"#,
+                "\n",
+                r#"{"step":1,"ip":1,"ins":"Shift(1)","ptr":1,"cell":0}"#,
+                "\n",
+                r#"{"step":2,"ip":2,"ins":"Alt(1)","ptr":1,"cell":1}"#,
+                "\n",
+                r#"{"step":3,"ip":3,"ins":"Shift(-1)","ptr":0,"cell":2}"#,
+                "\n",
+                r#"{"step":4,"ip":4,"ins":"Clear","ptr":0,"cell":0}"#,
+                "\n",
+            ),
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::default().steps(2..100).kinds("loop,end").unwrap();
+        let out = trace("+++[>+>+<<-]", Format::Json, filter);
+        let out = String::from_utf8(out).unwrap();
+
+        // the loop runs 3 times, and its only `Loop` is step 1
+        assert_eq!(3, out.lines().count());
+        assert!(out.lines().all(|line| line.contains(r#""ins":"End""#)));
+        assert!(Filter::default().kinds("in,bogus").is_err());
+    }
+
+    #[test]
+    fn test_resumed() {
+        let mut vm = VM::new_with_io(compile("+++>+"), MemoryIO::new(""));
+        vm.run_steps(1).unwrap();
+
+        let filter = Filter::default().steps(1..2);
+        let mut tracer = Tracer::new(Vec::new(), Format::Json, filter).unwrap();
+        vm.run_observed(u64::MAX, &mut tracer).unwrap();
+        let out = String::from_utf8(tracer.finish().unwrap()).unwrap();
+
+        assert_eq!(
+            concat!(r#"{"step":1,"ip":1,"ins":"Shift(1)","ptr":1,"cell":0}"#, "\n"),
+            out
+        );
+    }
+
+    #[test]
+    fn test_binary() {
+        let out = trace("+>", Format::Binary, Filter::default());
+
+        assert_eq!(6 + 2 * 22, out.len());
+        assert_eq!(b"BFTR\x01\x00", &out[..6]);
+        // second record: step 1, ip 1, Shift, ptr 1, cell 0
+        let record = &out[6 + 22..];
+        assert_eq!(1, u64::from_le_bytes(record[0..8].try_into().unwrap()));
+        assert_eq!(1, u32::from_le_bytes(record[8..12].try_into().unwrap()));
+        assert_eq!(0, record[12]);
+        assert_eq!(1, i64::from_le_bytes(record[13..21].try_into().unwrap()));
+        assert_eq!(0, record[21]);
+    }
+
+    #[test]
+    fn test_parse_steps() {
+        assert_eq!(Ok(100..200), parse_steps("100..200"));
+        assert_eq!(Ok(5..u64::MAX), parse_steps("5.."));
+        assert_eq!(Ok(0..7), parse_steps("..7"));
+        assert!(parse_steps("5").is_err());
+        assert!(parse_steps("a..b").is_err());
+    }
+}
@@ -0,0 +1,456 @@
+//! The brainfuck `Visualizer`. Runs a program in a full-screen
+//! terminal view, showing the source it was compiled from with the
+//! running command highlighted, the memory around the pointer and
+//! the output so far. Built on a `ResumableVM`, so the view keeps
+//! drawing and reading keys while the program waits for input.
+
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType},
+};
+
+use crate::{
+    compiler::{Program, Span},
+    resumable::{ResumableVM, State},
+};
+
+/// Instructions run per second at each speed
+const SPEEDS: [u64; 13] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 10_000, 100_000, 1_000_000,
+];
+
+/// Speed a visualizer starts at, 10 instructions per second
+const DEFAULT_SPEED: usize = 3;
+
+/// Time between frames
+const FRAME: Duration = Duration::from_millis(33);
+
+/// Columns taken by each memory cell
+const CELL_WIDTH: usize = 6;
+
+/// Lines of output shown
+const OUTPUT_LINES: usize = 6;
+
+/// Lines of the screen that aren't source: the status line, memory
+/// and output with their titles, and the controls
+const FIXED_LINES: usize = 1 + 4 + 1 + OUTPUT_LINES + 1;
+
+const CONTROLS: &str = "space pause/resume  s step  +/- speed  q quit";
+
+const INPUT_CONTROLS: &str = "type input for the program  enter newline  ctrl-d end of input  esc quit";
+
+/// A line of the screen, as runs of text and whether each is highlighted
+pub type Line = Vec<(String, bool)>;
+
+/// What the program is doing
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Status {
+    Running,
+    NeedsInput,
+    Finished,
+    Failed(String),
+}
+
+/// Full-screen terminal view of a running program
+pub struct Visualizer {
+    vm: ResumableVM,
+    /// Source the program was compiled from
+    src: String,
+    /// Everything the program has written
+    output: Vec<u8>,
+    status: Status,
+    paused: bool,
+    /// Index into `SPEEDS`
+    speed: usize,
+    /// Whether input is typed in as the program needs it, rather than given up front
+    interactive: bool,
+}
+
+impl Visualizer {
+    /// Create a visualizer for a program compiled from `src`. The program
+    /// reads `input` if given, otherwise input is typed in when it needs it.
+    pub fn new(program: Program, src: String, input: Option<Vec<u8>>) -> Self {
+        let mut vm = ResumableVM::new(program);
+        let interactive = input.is_none();
+        if let Some(input) = input {
+            vm.push_input(input);
+            vm.close_input();
+        }
+
+        Visualizer {
+            vm,
+            src,
+            output: Vec::new(),
+            status: Status::Running,
+            paused: false,
+            speed: DEFAULT_SPEED,
+            interactive,
+        }
+    }
+
+    /// Run at the fastest speed that is at most `steps_per_second`
+    pub fn set_speed(&mut self, steps_per_second: u64) {
+        self.speed = SPEEDS
+            .iter()
+            .rposition(|&speed| speed <= steps_per_second)
+            .unwrap_or(0);
+    }
+
+    /// Everything the program has written so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Show the visualizer on the terminal until the user quits
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut out = io::stdout();
+        let _terminal = FullScreen::enter(&mut out)?;
+
+        // instructions due at the current speed, carried between frames
+        let mut due = 0.0;
+        let mut last = Instant::now();
+        loop {
+            let (width, height) = terminal::size()?;
+            self.draw(&mut out, width as usize, height as usize)?;
+
+            if event::poll(FRAME)? {
+                match event::read()? {
+                    Event::Key(key) if key.kind == KeyEventKind::Press && !self.key(key) => return Ok(()),
+                    Event::Resize(..) => queue!(out, terminal::Clear(ClearType::All))?,
+                    _ => {}
+                }
+            }
+
+            let now = Instant::now();
+            match !self.paused && self.status == Status::Running {
+                true => {
+                    due += (now - last).as_secs_f64() * SPEEDS[self.speed] as f64;
+                    let steps = due as u64;
+                    due -= steps as f64;
+                    self.step(steps);
+                }
+                false => due = 0.0,
+            }
+            last = now;
+        }
+    }
+
+    /// Run at most `limit` instructions, stopping early
+    /// when the program needs input, ends or fails
+    pub fn step(&mut self, limit: u64) {
+        let mut remaining = limit;
+        while remaining > 0 && self.status == Status::Running {
+            let start = self.vm.steps();
+            match self.vm.resume_steps(remaining) {
+                Ok(Some(State::Output(bytes))) => self.output.extend(bytes),
+                Ok(Some(State::NeedsInput)) => self.status = Status::NeedsInput,
+                Ok(Some(State::Finished)) => self.status = Status::Finished,
+                Ok(None) => {}
+                Err(err) => self.status = Status::Failed(err.to_string()),
+            }
+            remaining -= self.vm.steps() - start;
+        }
+    }
+
+    /// Handle a key press, returning `false` once the visualizer should close
+    pub fn key(&mut self, key: KeyEvent) -> bool {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') if ctrl => return false,
+            // while the program waits for input, keys are its input
+            code if self.interactive && self.status == Status::NeedsInput => self.input(code, ctrl),
+            KeyCode::Char('q') => return false,
+            KeyCode::Char(' ') => self.paused = !self.paused,
+            KeyCode::Char('s') | KeyCode::Right => {
+                self.paused = true;
+                self.step(1);
+            }
+            KeyCode::Char('+' | '=') | KeyCode::Up => {
+                self.speed = (self.speed + 1).min(SPEEDS.len() - 1);
+            }
+            KeyCode::Char('-') | KeyCode::Down => self.speed = self.speed.saturating_sub(1),
+            _ => {}
+        }
+
+        true
+    }
+
+    /// Pass a typed key to the program as input
+    fn input(&mut self, code: KeyCode, ctrl: bool) {
+        match code {
+            KeyCode::Char('d') if ctrl => self.vm.close_input(),
+            KeyCode::Char(c) => self.vm.push_input(c.to_string()),
+            KeyCode::Enter => self.vm.push_input("\n"),
+            KeyCode::Tab => self.vm.push_input("\t"),
+            _ => return,
+        }
+
+        self.status = Status::Running;
+    }
+
+    /// Draw the screen for a terminal of the given size
+    fn draw(&self, out: &mut impl Write, width: usize, height: usize) -> io::Result<()> {
+        for (row, line) in self.render(width, height).into_iter().enumerate() {
+            queue!(
+                out,
+                cursor::MoveTo(0, row as u16),
+                terminal::Clear(ClearType::CurrentLine)
+            )?;
+            for (text, highlight) in line {
+                match highlight {
+                    true => queue!(
+                        out,
+                        SetAttribute(Attribute::Reverse),
+                        Print(text),
+                        SetAttribute(Attribute::Reset)
+                    )?,
+                    false => queue!(out, Print(text))?,
+                }
+            }
+        }
+
+        out.flush()
+    }
+
+    /// Lay out the screen for a terminal of the given size
+    pub fn render(&self, width: usize, height: usize) -> Vec<Line> {
+        let vm = self.vm.vm();
+        let status = match (&self.status, self.paused) {
+            (Status::Running, true) => "paused".to_string(),
+            (Status::Running, false) => "running".to_string(),
+            (Status::NeedsInput, _) => "waiting for input".to_string(),
+            (Status::Finished, _) => "finished".to_string(),
+            (Status::Failed(err), _) => format!("error: {err}"),
+        };
+        let header = format!(" step {}  {}/s  {status}", vm.steps(), SPEEDS[self.speed]);
+
+        let mut lines = vec![vec![(pad(&header, width), true)]];
+        lines.extend(self.source(width, height.saturating_sub(FIXED_LINES)));
+        lines.push(title(&format!("memory, ptr {}", vm.ptr() as isize), width));
+        lines.extend(self.tape(width));
+        lines.push(title("output", width));
+        lines.extend(self.output_lines(width));
+        lines.push(vec![(
+            match self.interactive && self.status == Status::NeedsInput {
+                true => INPUT_CONTROLS,
+                false => CONTROLS,
+            }
+            .chars()
+            .take(width)
+            .collect(),
+            false,
+        )]);
+
+        lines.truncate(height);
+        lines
+    }
+
+    /// Source of the instruction that runs next, if any
+    fn current_span(&self) -> Option<Span> {
+        let vm = self.vm.vm();
+        match vm.is_finished() {
+            true => None,
+            false => vm.program().source_map.as_ref().map(|map| map[vm.ip()]),
+        }
+    }
+
+    /// Source lines around the instruction that runs next, with its source highlighted
+    fn source(&self, width: usize, height: usize) -> Vec<Line> {
+        let span = self.current_span();
+
+        // byte offset each line starts at
+        let mut starts = vec![0];
+        starts.extend(self.src.match_indices('\n').map(|(i, _)| i + 1));
+
+        let (current, column) = match span {
+            Some(span) => span.line_col(&self.src),
+            None => (1, 1),
+        };
+        let first = (current - 1)
+            .saturating_sub(height / 2)
+            .min(starts.len().saturating_sub(height));
+        // scroll sideways so the current command is in view
+        let skip = match column >= width {
+            true => column - width / 2,
+            false => 0,
+        };
+
+        let mut lines: Vec<Line> = starts
+            .iter()
+            .skip(first)
+            .take(height)
+            .map(|&start| {
+                let text = self.src[start..].split('\n').next().unwrap_or("");
+                let mut line: Line = Vec::new();
+                for (i, c) in text.char_indices().skip(skip).take(width) {
+                    let highlight = span.is_some_and(|span| (span.start..span.end).contains(&(start + i)));
+                    let c = if c.is_control() { ' ' } else { c };
+                    match line.last_mut() {
+                        Some((run, run_highlight)) if *run_highlight == highlight => run.push(c),
+                        _ => line.push((c.to_string(), highlight)),
+                    }
+                }
+                line
+            })
+            .collect();
+
+        lines.resize(height, Vec::new());
+        lines
+    }
+
+    /// Indices, values and characters of the cells around the pointer,
+    /// with the current cell highlighted
+    fn tape(&self, width: usize) -> Vec<Line> {
+        let vm = self.vm.vm();
+        let data = vm.data();
+        let ptr = vm.ptr();
+        let count = (width / CELL_WIDTH).clamp(1, data.len());
+        let start = ptr.saturating_sub(count / 2).min(data.len() - count);
+
+        let mut lines = vec![Vec::new(), Vec::new(), Vec::new()];
+        for (cell, &value) in data.iter().enumerate().skip(start).take(count) {
+            let c = match value {
+                b' '..=b'~' => value as char,
+                _ => ' ',
+            };
+            let columns = [cell.to_string(), value.to_string(), c.to_string()];
+            for (line, column) in lines.iter_mut().zip(columns) {
+                line.push((format!("{column:>width$}", width = CELL_WIDTH - 1), cell == ptr));
+                line.push((" ".to_string(), false));
+            }
+        }
+
+        lines
+    }
+
+    /// The last lines of output
+    fn output_lines(&self, width: usize) -> Vec<Line> {
+        let output = String::from_utf8_lossy(&self.output);
+        let all: Vec<&str> = output.split('\n').collect();
+
+        let mut lines: Vec<Line> = all[all.len().saturating_sub(OUTPUT_LINES)..]
+            .iter()
+            .map(|line| {
+                let text = line
+                    .chars()
+                    .map(|c| if c.is_control() { ' ' } else { c })
+                    .take(width)
+                    .collect();
+                vec![(text, false)]
+            })
+            .collect();
+
+        lines.resize(OUTPUT_LINES, Vec::new());
+        lines
+    }
+}
+
+/// Pad or cut `text` to `width` characters
+fn pad(text: &str, width: usize) -> String {
+    format!("{:width$.width$}", text, width = width)
+}
+
+/// A line titling the part of the screen below it
+fn title(text: &str, width: usize) -> Line {
+    let rule = "─".repeat(width.saturating_sub(text.chars().count() + 4));
+    vec![(format!("── {text} {rule}").chars().take(width).collect(), false)]
+}
+
+/// Switches the terminal to a full-screen raw mode, switching it back when dropped,
+/// even if the visualizer fails
+struct FullScreen;
+
+impl FullScreen {
+    fn enter(out: &mut impl Write) -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+        Ok(FullScreen)
+    }
+}
+
+impl Drop for FullScreen {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::compile;
+
+    use super::*;
+
+    fn open(src: &str, input: Option<&str>) -> Visualizer {
+        Visualizer::new(compile(src), src.to_string(), input.map(|s| s.into()))
+    }
+
+    fn text(line: &Line) -> String {
+        line.iter().map(|(text, _)| text.as_str()).collect()
+    }
+
+    fn highlighted(lines: &[Line]) -> Vec<&str> {
+        lines
+            .iter()
+            .flatten()
+            .filter(|(_, highlight)| *highlight)
+            .map(|(text, _)| text.trim())
+            .collect()
+    }
+
+    #[test]
+    fn test_render() {
+        let mut visualizer = open("++ add two\n[>+<-] move it\n>.", None);
+        visualizer.step(1);
+        let lines = visualizer.render(40, 20);
+
+        assert_eq!(20, lines.len());
+        // the status line, the copy loop the optimizer replaced, and the current cell
+        assert_eq!(
+            ["step 1  10/s  running", "[>+<-]", "0", "2", ""],
+            highlighted(&lines)[..]
+        );
+        assert_eq!("++ add two", text(&lines[1]));
+        assert!(text(&lines[8]).starts_with("── memory, ptr 0 ──"));
+        assert_eq!(40, text(&lines[8]).chars().count());
+
+        visualizer.step(10);
+        let lines = visualizer.render(40, 20);
+        assert!(text(&lines[0]).contains("finished"));
+        assert_eq!("    0     1 ", &text(&lines[9])[..12]);
+        assert_eq!("    0     2 ", &text(&lines[10])[..12]);
+        // the output is a control character, shown as a space
+        assert_eq!(" ", text(&lines[13]));
+    }
+
+    #[test]
+    fn test_input() {
+        let mut visualizer = open(",.,.", None);
+        visualizer.step(100);
+        assert_eq!(Status::NeedsInput, visualizer.status);
+        assert!(text(visualizer.render(80, 20).last().unwrap()).starts_with("type input"));
+
+        // keys that are controls otherwise are input while the program waits for it
+        assert!(visualizer.key(KeyEvent::new(KeyCode::Char('q'), KeyModifiers::NONE)));
+        visualizer.step(100);
+        assert!(visualizer.key(KeyEvent::new(KeyCode::Char('d'), KeyModifiers::CONTROL)));
+        visualizer.step(100);
+        assert_eq!(Status::Finished, visualizer.status);
+        assert_eq!(b"q\0", visualizer.output());
+        assert!(!visualizer.key(KeyEvent::new(KeyCode::Char('q'), KeyModifiers::NONE)));
+
+        // input given up front
+        let mut visualizer = open(",.", Some("x"));
+        visualizer.step(100);
+        assert_eq!(b"x", visualizer.output());
+    }
+}
@@ -2,16 +2,54 @@
 //! Brainfuck `Program`. Can be configured
 //! to use different `InputOutput` implementations.
 
-use std::fmt::Display;
+use std::{fmt::Display, io};
 
 use crate::{
     compiler::Program,
+    dump,
     instruction::Instruction::*,
-    io::{InputOutput, StdIO},
+    io::{InputOutput, StdIO, StepCounter},
+    observer::Observer,
+    snapshot::Snapshot,
 };
 
 /// Default memory size for VM.
-const MEM: usize = 30_000;
+pub(crate) const MEM: usize = 30_000;
+
+/// Error stopping a VM
+#[derive(Debug)]
+pub enum VMError {
+    /// The `InputOutput` implementation failed
+    Io(io::Error),
+    /// Instruction `ip` used the memory pointer `ptr`, which is outside of memory
+    PointerOutOfBounds { ip: usize, ptr: isize },
+}
+
+impl Display for VMError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VMError::Io(err) => write!(f, "{err}"),
+            VMError::PointerOutOfBounds { ip, ptr } => {
+                write!(f, "memory pointer {ptr} is out of bounds at instruction {ip}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VMError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VMError::Io(err) => Some(err),
+            VMError::PointerOutOfBounds { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for VMError {
+    fn from(err: io::Error) -> Self {
+        VMError::Io(err)
+    }
+}
 
 /// Brainfuck VM.
 #[derive(Debug, Clone)]
@@ -27,27 +65,50 @@ pub struct VM<IO: InputOutput> {
 
     /// InputOutput implementation
     io: IO,
+
+    /// Instruction to run next, kept between runs
+    ip: usize,
+
+    /// Number of instructions executed
+    steps: u64,
+
+    /// Bytes of input read
+    input_pos: u64,
+
+    /// Bytes of output written
+    output_pos: u64,
+
+    /// Counter kept at `steps` for the `IO`, set by `share_steps`
+    shared_steps: Option<StepCounter>,
 }
 
-/// Pretty view of brainfuck VM state
+/// Pretty view of brainfuck VM state.
+/// Shows memory as a `dump::hex`, or with a width
+/// (like `{:8}`) that many cells either side of the pointer.
 impl<IO: InputOutput> Display for VM<IO> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut buf = String::from("{\n");
         buf.push_str(&format!("\tptr: {}\n", self.ptr));
-        let mut last_nonzero = 0;
-        for i in 0..MEM {
-            if self.data[i] != 0 {
-                last_nonzero = i;
+
+        if let Some(width) = f.width() {
+            let start = self.ptr.saturating_sub(width).min(MEM);
+            let end = self.ptr.saturating_add(width + 1).clamp(start, MEM);
+            buf.push_str(&format!("\tmem[{start}..{end}]: ["));
+            for (i, b) in self.data[start..end].iter().enumerate() {
+                match start + i == self.ptr {
+                    true => buf.push_str(&format!("({}), ", b)),
+                    false => buf.push_str(&format!("{}, ", b)),
+                }
             }
+
+            return write!(f, "{}]\n}}", buf);
         }
 
-        let used_mem = &self.data[..=last_nonzero];
-        buf.push_str("\tmem: [");
-        for b in used_mem {
-            buf.push_str(&format!("{}, ", b));
+        for line in dump::hex(&self.data[..]).lines() {
+            buf.push_str(&format!("\t{line}\n"));
         }
 
-        write!(f, "{}]\n}}", buf)
+        write!(f, "{}}}", buf)
     }
 }
 
@@ -55,7 +116,7 @@ impl VM<StdIO> {
     /// Create a new Brainfuck VM to execute the given Program.
     /// Configured to use Stdin and Stdout.
     pub fn new(program: Program) -> Self {
-        VM::new_with_io(program, StdIO {})
+        VM::new_with_io(program, StdIO::new())
     }
 }
 
@@ -68,66 +129,288 @@ impl<IO: InputOutput> VM<IO> {
             data: Box::new([0; MEM]),
             ptr: 0,
             io,
+            ip: 0,
+            steps: 0,
+            input_pos: 0,
+            output_pos: 0,
+            shared_steps: None,
+        }
+    }
+
+    /// Keep `counter` at the number of steps run whenever the
+    /// VM reads or writes, so the `IO` can tell when it's called
+    pub fn share_steps(&mut self, counter: StepCounter) {
+        counter.set(self.steps);
+        self.shared_steps = Some(counter);
+    }
+
+    /// Runs the VM until the program ends or fails.
+    /// Reading past the end of input stores 0 in the current cell.
+    ///
+    /// The VM stops on the instruction that failed, and the next
+    /// call to `run` retries it, so an `InputOutput` returning
+    /// `ErrorKind::WouldBlock` pauses the program rather than ending it.
+    pub fn run(&mut self) -> Result<(), VMError> {
+        self.run_steps(u64::MAX)
+    }
+
+    /// Runs the VM like `run`, but stops after at most `limit` instructions.
+    /// Calling `run` or `run_steps` again continues where it stopped.
+    pub fn run_steps(&mut self, limit: u64) -> Result<(), VMError> {
+        self.run_observed(limit, &mut ())
+    }
+
+    /// Runs the VM like `run_steps`, calling back `observer` as it runs,
+    /// and stopping early if one of its callbacks breaks.
+    pub fn run_observed<O: Observer>(&mut self, limit: u64, observer: &mut O) -> Result<(), VMError> {
+        match limit {
+            u64::MAX => self.dispatch::<O, false>(limit, observer),
+            _ => self.dispatch::<O, true>(limit, observer),
         }
     }
 
-    /// Runs the VM
-    pub fn run(mut self) {
-        let mut instruction_ptr = 0;
+    /// The dispatch loop. Only checks the step `limit` if `LIMITED`,
+    /// as checking it on every instruction slows down a plain `run`.
+    fn dispatch<O: Observer, const LIMITED: bool>(
+        &mut self, limit: u64, observer: &mut O,
+    ) -> Result<(), VMError> {
+        // counted locally so they can stay in registers, and stored back
+        // whenever the IO, the caller or a `STEPPED` observer can see them
+        let mut instruction_ptr = self.ip;
+        let mut steps = self.steps;
+        let last_step = steps.saturating_add(limit);
+
+        while instruction_ptr < self.program.instructions.len() && (!LIMITED || steps < last_step) {
+            // current instruction to execute, loops below
+            // overwrite instruction_ptr when they jump
+            let ip = instruction_ptr;
+            let instruction = &self.program.instructions[ip];
+            if observer.before(self, ip).is_break() {
+                break;
+            }
 
-        while instruction_ptr < self.program.instructions.len() {
-            // current instruction to execute
-            let instruction = &self.program.instructions[instruction_ptr];
+            // the current cell, failing if the pointer has left memory
+            macro_rules! cell {
+                () => {
+                    match self.data.get_mut(self.ptr) {
+                        Some(cell) => cell,
+                        None => return Err(self.out_of_bounds(instruction_ptr, steps, self.ptr)),
+                    }
+                };
+            }
 
             // instruction implementations
             match instruction {
                 Shift(count) => {
-                    self.ptr = (self.ptr as isize + count) as usize;
+                    self.ptr = self.ptr.wrapping_add_signed(*count);
                 }
                 Alt(amount) => {
-                    self.data[self.ptr] = match *amount >= 0 {
-                        true => self.data[self.ptr].wrapping_add(*amount as u8),
-                        false => self.data[self.ptr].wrapping_sub(-amount as u8),
+                    let cell = cell!();
+                    let before = *cell;
+                    *cell = match *amount >= 0 {
+                        true => cell.wrapping_add(*amount as u8),
+                        false => cell.wrapping_sub(-amount as u8),
                     };
+                    observer.write(self.ptr, before, *cell);
                 }
                 Out => {
-                    self.io.print(self.data[self.ptr]);
+                    let byte = *cell!();
+                    self.ip = instruction_ptr;
+                    self.steps = steps;
+                    self.publish_steps();
+                    self.io.print(byte)?;
+                    self.output_pos += 1;
+                    observer.output(byte);
                 }
                 In => {
-                    self.data[self.ptr] = self.io.getch();
-                }
-                Loop => {
-                    if self.data[self.ptr] == 0u8 {
-                        instruction_ptr = self.program.loop_map[instruction_ptr];
-                    }
-                }
-                End => {
-                    if self.data[self.ptr] != 0u8 {
-                        instruction_ptr = self.program.loop_map[instruction_ptr];
+                    let before = *cell!();
+                    self.ip = instruction_ptr;
+                    self.steps = steps;
+                    self.publish_steps();
+                    let input = self.io.getch()?;
+                    if input.is_some() {
+                        self.input_pos += 1;
                     }
+                    self.data[self.ptr] = input.unwrap_or(0);
+                    observer.input(input);
+                    observer.write(self.ptr, before, self.data[self.ptr]);
                 }
+                Loop => match *cell!() == 0u8 {
+                    true => instruction_ptr = self.program.loop_map[instruction_ptr],
+                    false => observer.enter_loop(ip),
+                },
+                End => match *cell!() != 0u8 {
+                    true => instruction_ptr = self.program.loop_map[instruction_ptr],
+                    false => observer.exit_loop(ip),
+                },
                 Clear => {
                     // optimized version of [-]
-                    self.data[self.ptr] = 0u8;
+                    let before = std::mem::take(cell!());
+                    observer.write(self.ptr, before, 0);
                 }
                 CopyClear { mul, offset } => {
-                    let target_d_ptr = ((self.ptr as isize + offset) as usize) % MEM;
-                    let new_value = self.data[target_d_ptr].wrapping_add(self.data[self.ptr] * mul);
-                    self.data[self.ptr] = 0u8;
-                    self.data[target_d_ptr] = new_value;
+                    // like the loop it replaces, the target cell is only
+                    // touched, so only has to be in memory, when the current cell is nonzero
+                    let value = *cell!();
+                    let target = self.ptr.wrapping_add_signed(*offset);
+                    if value != 0 && target >= MEM {
+                        return Err(self.out_of_bounds(instruction_ptr, steps, target));
+                    }
+
+                    self.data[self.ptr] = 0;
+                    observer.write(self.ptr, value, 0);
+                    if value != 0 {
+                        let before = self.data[target];
+                        self.data[target] = before.wrapping_add(value.wrapping_mul(*mul));
+                        observer.write(target, before, self.data[target]);
+                    }
                 }
             };
 
             instruction_ptr += 1;
+            steps += 1;
+            // also up to date for the next `before`
+            if O::STEPPED {
+                self.ip = instruction_ptr;
+                self.steps = steps;
+            }
+            if observer.after(self, ip).is_break() {
+                break;
+            }
+        }
+
+        self.ip = instruction_ptr;
+        self.steps = steps;
+        Ok(self.io.flush()?)
+    }
+
+    /// Update the shared step counter, if there is one
+    #[inline]
+    fn publish_steps(&self) {
+        if let Some(counter) = &self.shared_steps {
+            counter.set(self.steps);
+        }
+    }
+
+    /// Stop on instruction `ip`, which used the pointer `ptr` outside of memory.
+    /// Kept out of line, so the check doesn't slow down the dispatch loop.
+    #[cold]
+    fn out_of_bounds(&mut self, ip: usize, steps: u64, ptr: usize) -> VMError {
+        self.ip = ip;
+        self.steps = steps;
+        VMError::PointerOutOfBounds {
+            ip,
+            ptr: ptr as isize,
+        }
+    }
+
+    /// Capture the state of the VM, to `restore` it later
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            program: self.program.clone(),
+            data: self.data.clone(),
+            ptr: self.ptr,
+            ip: self.ip,
+            steps: self.steps,
+            input_pos: self.input_pos,
+            output_pos: self.output_pos,
         }
     }
+
+    /// Return the VM to the state in `snapshot`, including its program.
+    /// The VM keeps its `IO`, which should continue from
+    /// the snapshot's input and output positions.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.program = snapshot.program.clone();
+        self.data = snapshot.data.clone();
+        self.ptr = snapshot.ptr;
+        self.ip = snapshot.ip;
+        self.steps = snapshot.steps;
+        self.input_pos = snapshot.input_pos;
+        self.output_pos = snapshot.output_pos;
+    }
+
+    /// Index of the instruction to run next
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Memory pointer
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// Program memory
+    pub fn data(&self) -> &[u8] {
+        &self.data[..]
+    }
+
+    /// Program being executed
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// Whether the program has run to completion
+    pub fn is_finished(&self) -> bool {
+        self.ip >= self.program.instructions.len()
+    }
+
+    /// Append `program` to the program being executed.
+    /// The next `run` continues from where the VM stopped,
+    /// on the same memory, into the appended instructions.
+    pub fn append(&mut self, program: Program) {
+        self.program.append(program);
+    }
+
+    /// Number of instructions executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Bytes of input read so far
+    pub fn input_pos(&self) -> u64 {
+        self.input_pos
+    }
+
+    /// Bytes of output written so far
+    pub fn output_pos(&self) -> u64 {
+        self.output_pos
+    }
+
+    /// Move the VM back (or forward) to a position it was at before,
+    /// memory is left for the caller to restore
+    pub(crate) fn rewind(&mut self, ip: usize, ptr: usize, steps: u64, input_pos: u64, output_pos: u64) {
+        self.ip = ip;
+        self.ptr = ptr;
+        self.steps = steps;
+        self.input_pos = input_pos;
+        self.output_pos = output_pos;
+    }
+
+    /// Overwrite a memory cell
+    pub(crate) fn set_cell(&mut self, cell: usize, value: u8) {
+        self.data[cell] = value;
+    }
+
+    /// The VM's `InputOutput` implementation
+    pub fn io(&self) -> &IO {
+        &self.io
+    }
+
+    /// The VM's `InputOutput` implementation, mutably
+    pub fn io_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+
+    /// Unwrap the VM, returning its `InputOutput` implementation
+    pub fn into_io(self) -> IO {
+        self.io
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use std::rc::Rc;
-
-    use crate::{compiler::compile, io::TestIO};
+    use crate::{compiler::compile, io::MemoryIO};
 
     use super::*;
 
@@ -149,78 +432,131 @@ pub mod tests {
         assert_eq!(11, i.program.loop_map[10]);
     }
 
+    #[test]
+    fn test_append() {
+        let mut i = VM::new_with_io(compile("++>+++"), MemoryIO::new(""));
+        i.run().unwrap();
+
+        i.append(compile("[<+>-]<."));
+        i.run().unwrap();
+        assert_eq!(b"\x05", i.io().output());
+        assert_eq!(
+            "{\n\tptr: 0\n\t00000000  05 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|\n\t00000010  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|\n\t*\n\t00007530\n}",
+            i.to_string()
+        );
+    }
+
+    #[test]
+    fn test_pointer_out_of_bounds() {
+        let mut i = VM::new_with_io(compile("+.<."), MemoryIO::new(""));
+        let err = i.run().unwrap_err();
+
+        assert!(matches!(err, VMError::PointerOutOfBounds { ip: 3, ptr: -1 }));
+        // stopped on the failing instruction, after earlier output
+        assert_eq!(3, i.ip());
+        assert_eq!(b"\x01", i.io().output());
+    }
+
+    #[test]
+    fn test_copy_clear_bounds() {
+        // 72 * 4 wraps around
+        let src = format!("{}[->++++<]>.", "+".repeat(72));
+        let mut i = VM::new_with_io(compile(&src), MemoryIO::new(""));
+        i.run().unwrap();
+        assert_eq!(b" ", i.io().output());
+
+        // a copy out of memory fails, and leaves the current cell alone
+        let mut i = VM::new_with_io(compile("+[-<+>]"), MemoryIO::new(""));
+        let err = i.run().unwrap_err();
+        assert!(matches!(err, VMError::PointerOutOfBounds { ip: 1, ptr: -1 }));
+        assert_eq!(1, i.data()[0]);
+
+        // unless the loop wouldn't have run
+        let mut i = VM::new_with_io(compile("[-<+>]"), MemoryIO::new(""));
+        i.run().unwrap();
+    }
+
+    #[test]
+    fn test_run_steps() {
+        let mut i = VM::new_with_io(compile("+[]"), MemoryIO::new(""));
+        i.run_steps(100).unwrap();
+
+        assert_eq!(100, i.steps());
+        assert!(!i.is_finished());
+    }
+
+    #[test]
+    fn test_display_window() {
+        let mut i = VM::new_with_io(compile("+>++>>>+++<<"), MemoryIO::new(""));
+        i.run().unwrap();
+
+        assert_eq!("{\n\tptr: 2\n\tmem[1..4]: [2, (0), 0, ]\n}", format!("{i:1}"));
+        assert_eq!(
+            "{\n\tptr: 2\n\tmem[0..6]: [1, 2, (0), 0, 3, 0, ]\n}",
+            format!("{i:3}")
+        );
+    }
+
     #[test]
     fn test_hello_world() {
-        let src = include_str!("..\\samples\\helloworld.bf");
+        let src = include_str!("../samples/helloworld.bf");
         let p = compile(src);
-        let io = Rc::new(TestIO::new(""));
-        let io_clone = io.clone();
-        let i = VM::new_with_io(p, io);
+        let mut i = VM::new_with_io(p, MemoryIO::new(""));
 
-        i.run();
-        assert_eq!("Hello World!\n", io_clone.output());
+        i.run().unwrap();
+        assert_eq!("Hello World!\n".as_bytes(), i.io().output());
     }
 
     #[test]
     fn test_666() {
-        let src = include_str!("..\\samples\\666.bf");
+        let src = include_str!("../samples/666.bf");
         let p = compile(src);
-        let io = Rc::new(TestIO::new(""));
-        let io_clone = io.clone();
-        let i = VM::new_with_io(p, io);
+        let mut i = VM::new_with_io(p, MemoryIO::new(""));
 
-        i.run();
-        assert_eq!("666\n", io_clone.output());
+        i.run().unwrap();
+        assert_eq!("666\n".as_bytes(), i.io().output());
     }
 
     #[test]
     fn test_pi4() {
-        let src = include_str!("..\\samples\\pi4.bf");
+        let src = include_str!("../samples/pi4.bf");
         let p = compile(src);
-        let io = Rc::new(TestIO::new(""));
-        let io_clone = io.clone();
-        let i = VM::new_with_io(p, io);
+        let mut i = VM::new_with_io(p, MemoryIO::new(""));
 
-        i.run();
-        assert_eq!("3.141\n", io_clone.output());
+        i.run().unwrap();
+        assert_eq!("3.141\n".as_bytes(), i.io().output());
     }
 
     #[test]
     fn test_sierpinski() {
-        let src = include_str!("..\\samples\\sierpinski.bf");
-        let out = include_str!("..\\samples\\out\\sierpinski.txt");
+        let src = include_str!("../samples/sierpinski.bf");
+        let out = include_str!("../samples/out/sierpinski.txt");
         let p = compile(src);
-        let io = Rc::new(TestIO::new(""));
-        let io_clone = io.clone();
-        let i = VM::new_with_io(p, io);
+        let mut i = VM::new_with_io(p, MemoryIO::new(""));
 
-        i.run();
-        assert_eq!(out, io_clone.output());
+        i.run().unwrap();
+        assert_eq!(out.as_bytes(), i.io().output());
     }
 
     #[test]
     fn test_fib11() {
-        let src = include_str!("..\\samples\\fib11.bf");
+        let src = include_str!("../samples/fib11.bf");
         let p = compile(src);
-        let io = Rc::new(TestIO::new(""));
-        let io_clone = io.clone();
-        let i = VM::new_with_io(p, io);
+        let mut i = VM::new_with_io(p, MemoryIO::new(""));
 
-        i.run();
-        assert_eq!("1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89", io_clone.output());
+        i.run().unwrap();
+        assert_eq!("1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89".as_bytes(), i.io().output());
     }
 
     #[test]
     fn test_mandelbrot() {
-        let src = include_str!("..\\samples\\mandelbrot.bf");
-        let out = include_str!("..\\samples\\out\\mandelbrot.txt");
+        let src = include_str!("../samples/mandelbrot.bf");
+        let out = include_str!("../samples/out/mandelbrot.txt");
 
         let p = compile(src);
-        let io = Rc::new(TestIO::new(""));
-        let io_clone = io.clone();
-        let i = VM::new_with_io(p, io);
+        let mut i = VM::new_with_io(p, MemoryIO::new(""));
 
-        i.run();
-        assert_eq!(out, io_clone.output());
+        i.run().unwrap();
+        assert_eq!(out.as_bytes(), i.io().output());
     }
 }
@@ -0,0 +1,484 @@
+//! Brainfuck `Watchpoint`s and conditional `Breakpoint`s. A
+//! `Watcher` checks them after each instruction a `VM` runs,
+//! as an `Observer`, and says why the VM should stop. Write and
+//! value watchpoints go off on the writes the VM reports, so an
+//! instruction that could write a cell but didn't never hits them.
+//!
+//! Conditions compare cells, the memory pointer and numbers,
+//! joined with `&&`:
+//! ```text
+//! mem[3] == 10 && ptr > 5 && cell != 0
+//! ```
+//! `cell` is the current cell, and comparisons with cells
+//! outside of memory are false.
+
+use std::{collections::BTreeMap, fmt::Display, ops::ControlFlow, ops::Range, str::FromStr};
+
+use crate::{
+    history::CellWrite,
+    io::InputOutput,
+    observer::Observer,
+    vm::{VMError, MEM, VM},
+};
+
+/// A memory event to stop at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Watchpoint {
+    /// Any write to a memory cell, even of the value it already had
+    Write(usize),
+    /// A memory cell being set to a value
+    Value { cell: usize, value: u8 },
+    /// The memory pointer moving into a range of cells
+    Pointer(Range<usize>),
+}
+
+impl Watchpoint {
+    /// Whether an instruction that wrote `writes` and moved the
+    /// memory pointer from `before` to `after` hit this watchpoint
+    fn hit(&self, writes: &[CellWrite], before: usize, after: usize) -> bool {
+        match self {
+            Watchpoint::Write(cell) => writes.iter().any(|write| write.cell == *cell),
+            Watchpoint::Value { cell, value } => writes
+                .iter()
+                .any(|write| write.cell == *cell && write.after == *value),
+            Watchpoint::Pointer(range) => range.contains(&after) && !range.contains(&before),
+        }
+    }
+}
+
+impl Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Watchpoint::Write(cell) => write!(f, "write to cell {cell}"),
+            Watchpoint::Value { cell, value } => write!(f, "cell {cell} == {value}"),
+            Watchpoint::Pointer(range) => write!(f, "ptr in {}..{}", range.start, range.end),
+        }
+    }
+}
+
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    /// Parse a watchpoint like `3` (any write to cell 3),
+    /// `3 == 10` (cell 3 set to 10) or `ptr 5..8` (pointer moves into cells 5 to 7)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cell = |cell: &str| match cell.trim().parse() {
+            Ok(cell) if cell < MEM => Ok(cell),
+            Ok(cell) => Err(format!("cell {cell} is outside of memory")),
+            Err(_) => Err(format!("invalid cell `{}` in `{s}`", cell.trim())),
+        };
+
+        if let Some(range) = s.trim().strip_prefix("ptr") {
+            let (start, end) = range
+                .split_once("..")
+                .ok_or_else(|| format!("invalid pointer range in `{s}`, expected like `ptr 5..8`"))?;
+            let (start, end) = (cell(start)?, cell(end)?);
+            if start >= end {
+                return Err(format!(
+                    "empty pointer range in `{s}`, the start must be before the end"
+                ));
+            }
+            return Ok(Watchpoint::Pointer(start..end));
+        }
+
+        match s.split_once("==") {
+            Some((target, value)) => Ok(Watchpoint::Value {
+                cell: cell(target)?,
+                value: value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid cell value `{}` in `{s}`", value.trim()))?,
+            }),
+            None => Ok(Watchpoint::Write(cell(s)?)),
+        }
+    }
+}
+
+/// A value a `Condition` compares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    /// The memory pointer
+    Ptr,
+    /// The current cell
+    Cell,
+    /// A memory cell
+    Mem(usize),
+    Number(i64),
+}
+
+impl Operand {
+    fn value<IO: InputOutput>(&self, vm: &VM<IO>) -> Option<i64> {
+        match self {
+            Operand::Ptr => Some(vm.ptr() as isize as i64),
+            Operand::Cell => vm.data().get(vm.ptr()).map(|&cell| cell as i64),
+            Operand::Mem(cell) => vm.data().get(*cell).map(|&cell| cell as i64),
+            Operand::Number(number) => Some(*number),
+        }
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Ptr => write!(f, "ptr"),
+            Operand::Cell => write!(f, "cell"),
+            Operand::Mem(cell) => write!(f, "mem[{cell}]"),
+            Operand::Number(number) => write!(f, "{number}"),
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let operand = match s {
+            "ptr" => Operand::Ptr,
+            "cell" => Operand::Cell,
+            _ => match s.strip_prefix("mem[").and_then(|s| s.strip_suffix(']')) {
+                Some(cell) => Operand::Mem(
+                    cell.trim()
+                        .parse()
+                        .map_err(|_| format!("invalid cell in `{s}`"))?,
+                ),
+                None => Operand::Number(s.parse().map_err(|_| {
+                    format!("invalid operand `{s}`, expected `ptr`, `cell`, `mem[<cell>]` or a number")
+                })?),
+            },
+        };
+
+        Ok(operand)
+    }
+}
+
+/// How a `Condition` compares two operands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Lt,
+    Gt,
+}
+
+impl Comparison {
+    /// Every comparison, longest symbol first so `<=` isn't read as `<`
+    const ALL: [Comparison; 6] = [
+        Comparison::Eq,
+        Comparison::Ne,
+        Comparison::Le,
+        Comparison::Ge,
+        Comparison::Lt,
+        Comparison::Gt,
+    ];
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Le => "<=",
+            Comparison::Ge => ">=",
+            Comparison::Lt => "<",
+            Comparison::Gt => ">",
+        }
+    }
+
+    fn compare(&self, lhs: i64, rhs: i64) -> bool {
+        match self {
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ne => lhs != rhs,
+            Comparison::Le => lhs <= rhs,
+            Comparison::Ge => lhs >= rhs,
+            Comparison::Lt => lhs < rhs,
+            Comparison::Gt => lhs > rhs,
+        }
+    }
+}
+
+/// A predicate over the memory pointer and cell values
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    /// Comparisons that must all hold
+    terms: Vec<(Operand, Comparison, Operand)>,
+}
+
+impl Condition {
+    /// Whether the condition holds for the VM
+    pub fn holds<IO: InputOutput>(&self, vm: &VM<IO>) -> bool {
+        self.terms
+            .iter()
+            .all(|(lhs, comparison, rhs)| match (lhs.value(vm), rhs.value(vm)) {
+                (Some(lhs), Some(rhs)) => comparison.compare(lhs, rhs),
+                _ => false,
+            })
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (lhs, comparison, rhs)) in self.terms.iter().enumerate() {
+            if i > 0 {
+                write!(f, " && ")?;
+            }
+            write!(f, "{lhs} {} {rhs}", comparison.symbol())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let terms = s
+            .split("&&")
+            .map(|term| {
+                let (comparison, (lhs, rhs)) = Comparison::ALL
+                    .iter()
+                    .find_map(|&comparison| Some((comparison, term.split_once(comparison.symbol())?)))
+                    .ok_or_else(|| {
+                        format!("expected a comparison like `mem[0] == 1`, got `{}`", term.trim())
+                    })?;
+
+                Ok((lhs.trim().parse()?, comparison, rhs.trim().parse()?))
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Condition { terms })
+    }
+}
+
+/// Stop before running an instruction, optionally only when a condition holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub ip: usize,
+    pub condition: Option<Condition>,
+}
+
+impl Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.condition {
+            Some(condition) => write!(f, "[{}] if {condition}", self.ip),
+            None => write!(f, "[{}]", self.ip),
+        }
+    }
+}
+
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    /// Parse a breakpoint like `12`, or `12 if mem[3] == 0`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, condition) = match s.split_once(" if ") {
+            Some((ip, condition)) => (ip, Some(condition.parse()?)),
+            None => (s, None),
+        };
+
+        Ok(Breakpoint {
+            ip: ip
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid instruction index `{}`", ip.trim()))?,
+            condition,
+        })
+    }
+}
+
+/// Why a `Watcher` stopped a VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The breakpoint on instruction `ip` was reached
+    Breakpoint(usize),
+    /// The watchpoint with this index was hit
+    Watchpoint(usize),
+}
+
+/// Breakpoints and watchpoints to stop a VM at
+#[derive(Debug, Clone, Default)]
+pub struct Watcher {
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    /// Why the VM last stopped while observed
+    stopped: Option<Stop>,
+    /// Memory pointer before the instruction running, while observing a VM
+    ptr: usize,
+    /// Cells written by the instruction running, while observing a VM
+    writes: Vec<CellWrite>,
+}
+
+impl Watcher {
+    /// Create a watcher with no breakpoints or watchpoints
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop before running instruction `breakpoint.ip`,
+    /// replacing any breakpoint already on it
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.insert(breakpoint.ip, breakpoint);
+    }
+
+    /// Remove the breakpoint on instruction `ip`, returning whether there was one
+    pub fn remove_breakpoint(&mut self, ip: usize) -> bool {
+        self.breakpoints.remove(&ip).is_some()
+    }
+
+    /// Breakpoints, by instruction index
+    pub fn breakpoints(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.values()
+    }
+
+    /// Whether there is a breakpoint on instruction `ip`, whatever its condition
+    pub fn has_breakpoint(&self, ip: usize) -> bool {
+        self.breakpoints.contains_key(&ip)
+    }
+
+    /// Stop at a watchpoint, returning its index
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watchpoints.push(watchpoint);
+        self.watchpoints.len() - 1
+    }
+
+    /// Remove the watchpoint with the given index,
+    /// the indices of later watchpoints shift down
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        (index < self.watchpoints.len()).then(|| self.watchpoints.remove(index))
+    }
+
+    /// Watchpoints, by index
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Index of the first watchpoint hit by an instruction that
+    /// wrote `writes` and moved the memory pointer from `before` to `after`
+    pub fn watchpoint(&self, writes: &[CellWrite], before: usize, after: usize) -> Option<usize> {
+        self.watchpoints
+            .iter()
+            .position(|watchpoint| watchpoint.hit(writes, before, after))
+    }
+
+    /// Whether the VM is at a breakpoint whose condition holds
+    pub fn at_breakpoint<IO: InputOutput>(&self, vm: &VM<IO>) -> bool {
+        match self.breakpoints.get(&vm.ip()) {
+            Some(breakpoint) => breakpoint
+                .condition
+                .as_ref()
+                .is_none_or(|condition| condition.holds(vm)),
+            None => false,
+        }
+    }
+
+    /// Why the VM should stop after an instruction that wrote `writes` and
+    /// moved the memory pointer from `ptr`, if it should. Watchpoints come
+    /// first, as they were hit by the instruction that just ran.
+    pub fn check<IO: InputOutput>(&self, vm: &VM<IO>, writes: &[CellWrite], ptr: usize) -> Option<Stop> {
+        if let Some(index) = self.watchpoint(writes, ptr, vm.ptr()) {
+            return Some(Stop::Watchpoint(index));
+        }
+
+        self.at_breakpoint(vm).then(|| Stop::Breakpoint(vm.ip()))
+    }
+
+    /// Run a VM for at most `limit` instructions, stopping early at a
+    /// breakpoint or watchpoint. At least one instruction runs, so
+    /// running again from a breakpoint moves past it.
+    pub fn run<IO: InputOutput>(&mut self, vm: &mut VM<IO>, limit: u64) -> Result<Option<Stop>, VMError> {
+        self.stopped = None;
+        vm.run_observed(limit, self)?;
+        Ok(self.stopped)
+    }
+
+    /// Why the VM stopped the last time it was observed, if it was stopped by this watcher
+    pub fn stopped(&self) -> Option<Stop> {
+        self.stopped
+    }
+}
+
+/// Stops the VM after an instruction that should stop it
+impl Observer for Watcher {
+    fn before<IO: InputOutput>(&mut self, vm: &VM<IO>, _ip: usize) -> ControlFlow<()> {
+        self.ptr = vm.ptr();
+        self.writes.clear();
+        ControlFlow::Continue(())
+    }
+
+    fn write(&mut self, cell: usize, before: u8, after: u8) {
+        self.writes.push(CellWrite { cell, before, after });
+    }
+
+    fn after<IO: InputOutput>(&mut self, vm: &VM<IO>, _ip: usize) -> ControlFlow<()> {
+        self.stopped = self.check(vm, &self.writes, self.ptr);
+        match self.stopped {
+            Some(_) => ControlFlow::Break(()),
+            None => ControlFlow::Continue(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::compile, io::MemoryIO};
+
+    use super::*;
+
+    #[test]
+    fn test_watchpoints() {
+        let mut vm = VM::new_with_io(compile("+++[>++<-.]>>>>"), MemoryIO::new(""));
+        let mut watcher = Watcher::new();
+        watcher.add_watchpoint("1 == 4".parse().unwrap());
+        watcher.add_watchpoint("ptr 3..10".parse().unwrap());
+
+        // the `.` keeps the loop from being optimized, so cell 1 reaches 4 in the second iteration
+        assert_eq!(Some(Stop::Watchpoint(0)), watcher.run(&mut vm, u64::MAX).unwrap());
+        assert_eq!((1, 4), (vm.ptr(), vm.data()[1]));
+        assert_eq!(Some(Stop::Watchpoint(1)), watcher.run(&mut vm, u64::MAX).unwrap());
+        assert_eq!(4, vm.ptr());
+        assert_eq!(None, watcher.run(&mut vm, u64::MAX).unwrap());
+        assert!(vm.is_finished());
+
+        assert_eq!(Ok(Watchpoint::Write(7)), "7".parse());
+        assert!("30000".parse::<Watchpoint>().is_err());
+        assert!("ptr 3".parse::<Watchpoint>().is_err());
+        assert!("ptr 8..5".parse::<Watchpoint>().is_err());
+        assert!("ptr 5..5".parse::<Watchpoint>().is_err());
+    }
+
+    #[test]
+    fn test_copy_clear_of_zero() {
+        // the copy loop runs on a cell that is already 0, so it writes nothing to cell 1
+        let mut vm = VM::new_with_io(compile(">+<[->+<]>."), MemoryIO::new(""));
+        let mut watcher = Watcher::new();
+        watcher.add_watchpoint("1".parse().unwrap());
+        watcher.add_watchpoint("1 == 1".parse().unwrap());
+
+        assert_eq!(Some(Stop::Watchpoint(0)), watcher.run(&mut vm, u64::MAX).unwrap());
+        assert_eq!(2, vm.ip());
+        assert_eq!(None, watcher.run(&mut vm, u64::MAX).unwrap());
+        assert_eq!(b"\x01", vm.io().output());
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        let mut vm = VM::new_with_io(compile("+++++[>+<-.]"), MemoryIO::new(""));
+        let mut watcher = Watcher::new();
+        let breakpoint: Breakpoint = "3 if ptr == 1 && mem[1] >= 2 && mem[0] != 1".parse().unwrap();
+        assert_eq!(
+            "[3] if ptr == 1 && mem[1] >= 2 && mem[0] != 1",
+            breakpoint.to_string()
+        );
+        watcher.add_breakpoint(breakpoint);
+
+        // stops before the `+` of the third and fourth iterations, not the fifth
+        for expected in [2, 3] {
+            assert_eq!(Some(Stop::Breakpoint(3)), watcher.run(&mut vm, u64::MAX).unwrap());
+            assert_eq!(expected, vm.data()[1]);
+        }
+        assert_eq!(None, watcher.run(&mut vm, u64::MAX).unwrap());
+
+        assert!("3 if mem[1]".parse::<Breakpoint>().is_err());
+        assert!("3 if foo == 1".parse::<Breakpoint>().is_err());
+    }
+}